@@ -0,0 +1,92 @@
+//! Keeps dBAR files on disk, so that repeated runs over the same disc do not query the database again.
+
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use failure;
+
+use offset::DiscInfo;
+use util::write_atomically;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct BinCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl BinCache {
+    /// Cached files older than `ttl` are treated as missing.
+    pub fn new<P: AsRef<Path>>(dir: P, ttl: Duration) -> Self {
+        BinCache { dir: dir.as_ref().to_path_buf(), ttl }
+    }
+
+    fn bin_path(&self, disc_info: &DiscInfo) -> PathBuf {
+        self.dir.join(format!("dBAR-{}.bin", disc_info.ar_disc_id()))
+    }
+
+    /// Returns the cached dBAR file for a disc, or `None` if it is not cached or has expired.
+    pub fn get(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+        let bin_path = self.bin_path(disc_info);
+
+        let modified = match fs::metadata(&bin_path) {
+            Ok(metadata) => metadata.modified()?,
+            Err(_) => return Ok(None),
+        };
+
+        // A modification time in the future is treated as fresh.
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+
+        if age >= self.ttl {
+            return Ok(None);
+        }
+
+        let mut ar_bin_data = vec![];
+        File::open(&bin_path)?.read_to_end(&mut ar_bin_data)?;
+
+        Ok(Some(ar_bin_data))
+    }
+
+    pub fn put(&self, disc_info: &DiscInfo, ar_bin_data: &[u8]) -> Result<(), failure::Error> {
+        fs::create_dir_all(&self.dir)?;
+
+        // An interrupted write never leaves a truncated file to be served.
+        write_atomically(self.bin_path(disc_info), ar_bin_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+    use std::time::Duration;
+
+    use self::tempfile::tempdir;
+
+    use offset::SAMPLE_DISC_INFO;
+
+    use super::BinCache;
+
+    #[test]
+    fn test_bin_cache() {
+        let temp_dir = tempdir().unwrap();
+        let cache_dir = temp_dir.path().join("cache");
+
+        let cache = BinCache::new(&cache_dir, Duration::from_secs(3600));
+        assert_eq!(None, cache.get(&SAMPLE_DISC_INFO).unwrap());
+
+        cache.put(&SAMPLE_DISC_INFO, &[1, 2, 3]).unwrap();
+        assert_eq!(Some(vec![1, 2, 3]), cache.get(&SAMPLE_DISC_INFO).unwrap());
+        assert!(cache_dir.join("dBAR-010-0012baaf-0094edfd-8b0b500a.bin").exists());
+        assert_eq!(1, fs::read_dir(&cache_dir).unwrap().count());
+
+        // Everything is expired with a zero TTL.
+        let expired_cache = BinCache::new(&cache_dir, Duration::from_secs(0));
+        assert_eq!(None, expired_cache.get(&SAMPLE_DISC_INFO).unwrap());
+    }
+}
@@ -0,0 +1,283 @@
+//! Calculates all per-track checksums reported by rippers in a single pass over decoded audio.
+
+use std::str::FromStr;
+
+use failure;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use crc::ar_window;
+use crc::calc_partial_ar_crcs;
+use crc::BLOCK_SAMPLES;
+use crc::CRC;
+use crc::CRCs;
+
+const CRC32_POLYNOMIAL: u32 = 0xEDB88320;
+
+/// Which checksums to calculate.
+/// AccurateRip v1 and v2 share the same per-sample multiplication, so they are selected together.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChecksumSelection {
+    pub ar: bool,
+    pub crc32: bool,
+    pub crc32_skip_zero: bool,
+    pub peak: bool,
+}
+
+impl ChecksumSelection {
+    pub fn all() -> Self {
+        ChecksumSelection { ar: true, crc32: true, crc32_skip_zero: true, peak: true }
+    }
+
+    pub fn none() -> Self {
+        ChecksumSelection { ar: false, crc32: false, crc32_skip_zero: false, peak: false }
+    }
+}
+
+impl FromStr for ChecksumSelection {
+    type Err = failure::Error;
+
+    /// Parses a comma-separated list such as `ar,crc32,peak`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut selection = ChecksumSelection::none();
+
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "ar" => selection.ar = true,
+                "crc32" => selection.crc32 = true,
+                "crc32-skip-zero" => selection.crc32_skip_zero = true,
+                "peak" => selection.peak = true,
+                _ => bail!("unknown checksum: {}", name),
+            }
+        }
+
+        Ok(selection)
+    }
+}
+
+/// Checksums that do not take part in AccurateRip matching, but appear in rip logs.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Serialize)]
+pub struct CopyChecksums {
+    /// CRC32 of the whole track, as reported by EAC's "Copy CRC" and XLD's "CRC32 hash".
+    pub crc32: Option<CRC>,
+    /// CRC32 of the track with all zero-valued 16-bit samples left out.
+    pub crc32_skip_zero: Option<CRC>,
+    /// Largest absolute sample value, where 32768 is full scale.
+    pub peak: Option<u16>,
+}
+
+impl CopyChecksums {
+    pub fn peak_percent(&self) -> Option<f64> {
+        self.peak.map(|peak| peak as f64 * 100.0 / 32768.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Checksums {
+    pub ar: Option<CRCs>,
+    pub copy: CopyChecksums,
+}
+
+fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut value = i as u32;
+
+        for _ in 0..8 {
+            value = if value & 1 != 0 { CRC32_POLYNOMIAL ^ (value >> 1) } else { value >> 1 };
+        }
+
+        *entry = value;
+    }
+
+    table
+}
+
+/// Incrementally calculates checksums over the little-endian, 16-bit stereo samples of a single track.
+/// Audio can be fed in arbitrarily sized pieces, so a track can be checksummed while it is being decoded.
+pub struct ChecksumEngine {
+    selection: ChecksumSelection,
+    crc32_table: [u32; 256],
+    window: (usize, usize),
+    multi: usize,
+    ar: CRCs,
+    crc32: u32,
+    crc32_skip_zero: u32,
+    peak: u16,
+    pending: Vec<u8>,
+}
+
+impl ChecksumEngine {
+    /// `track_len` is the total number of audio bytes in the track, which is needed up front
+    /// to know where the skipped region at the end of the last track begins.
+    pub fn new(selection: ChecksumSelection, track_len: usize, is_first: bool, is_last: bool) -> Self {
+        ChecksumEngine {
+            selection,
+            crc32_table: crc32_table(),
+            window: ar_window(track_len, is_first, is_last),
+            multi: 0,
+            ar: CRCs { v1: 0, v2: 0 },
+            crc32: !0,
+            crc32_skip_zero: !0,
+            peak: 0,
+            pending: Vec::with_capacity(4),
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        // Complete a sample that was split across calls.
+        if !self.pending.is_empty() {
+            let needed = (4 - self.pending.len()).min(bytes.len());
+            self.pending.extend_from_slice(&bytes[..needed]);
+            bytes = &bytes[needed..];
+
+            if self.pending.len() < 4 {
+                return;
+            }
+
+            let sample_bytes = [self.pending[0], self.pending[1], self.pending[2], self.pending[3]];
+            self.pending.clear();
+            self.process_samples(&sample_bytes);
+        }
+
+        let whole_len = bytes.len() / 4 * 4;
+
+        // Work in blocks, so that each block is still in cache for the second loop over it.
+        for block in bytes[..whole_len].chunks(BLOCK_SAMPLES * 4) {
+            self.process_samples(block);
+        }
+
+        self.pending.extend_from_slice(&bytes[whole_len..]);
+    }
+
+    #[inline]
+    fn update_crc32(table: &[u32; 256], crc: u32, bytes: &[u8]) -> u32 {
+        bytes.iter().fold(crc, |crc, &b| table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+    }
+
+    /// Processes a run of whole samples.
+    fn process_samples(&mut self, sample_bytes: &[u8]) {
+        if self.selection.ar {
+            let partial = calc_partial_ar_crcs(sample_bytes, self.multi + 1, self.window);
+            self.ar = self.ar.combine(partial);
+        }
+
+        self.multi += sample_bytes.len() / 4;
+
+        if self.selection.crc32 {
+            self.crc32 = Self::update_crc32(&self.crc32_table, self.crc32, sample_bytes);
+        }
+
+        if self.selection.crc32_skip_zero || self.selection.peak {
+            for channel_bytes in sample_bytes.chunks(2) {
+                let value = LittleEndian::read_i16(channel_bytes);
+
+                if self.selection.crc32_skip_zero && value != 0 {
+                    self.crc32_skip_zero = Self::update_crc32(&self.crc32_table, self.crc32_skip_zero, channel_bytes);
+                }
+
+                if self.selection.peak {
+                    self.peak = self.peak.max((value as i32).abs() as u16);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Result<Checksums, failure::Error> {
+        if !self.pending.is_empty() {
+            bail!("track audio is not a whole number of samples");
+        }
+
+        let selection = self.selection;
+
+        Ok(Checksums {
+            ar: if selection.ar { Some(self.ar) } else { None },
+            copy: CopyChecksums {
+                crc32: if selection.crc32 { Some(!self.crc32) } else { None },
+                crc32_skip_zero: if selection.crc32_skip_zero { Some(!self.crc32_skip_zero) } else { None },
+                peak: if selection.peak { Some(self.peak) } else { None },
+            },
+        })
+    }
+}
+
+/// Calculates the selected checksums over the audio of a whole track.
+pub fn calc_checksums(track_audio_bytes: &[u8], selection: ChecksumSelection, is_first: bool, is_last: bool) -> Result<Checksums, failure::Error> {
+    let mut engine = ChecksumEngine::new(selection, track_audio_bytes.len(), is_first, is_last);
+    engine.update(track_audio_bytes);
+    engine.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crc::calc_ar_crcs;
+
+    use super::calc_checksums;
+    use super::ChecksumEngine;
+    use super::ChecksumSelection;
+    use super::CopyChecksums;
+
+    const SAMPLES: &[u8] = &[
+        0x01, 0x00, 0xff, 0x7f,
+        0x00, 0x00, 0x00, 0x80,
+        0x34, 0x12, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        0xcd, 0xab, 0x01, 0x00,
+    ];
+
+    #[test]
+    fn test_calc_checksums() {
+        let produced = calc_checksums(SAMPLES, ChecksumSelection::all(), false, false).unwrap();
+
+        let expected_copy = CopyChecksums {
+            crc32: Some(0x50366bb1),
+            crc32_skip_zero: Some(0x6be18fa0),
+            peak: Some(32768),
+        };
+
+        assert_eq!(Some(calc_ar_crcs(SAMPLES, false, false).unwrap()), produced.ar);
+        assert_eq!(expected_copy, produced.copy);
+        assert_eq!(Some(100.0), produced.copy.peak_percent());
+    }
+
+    #[test]
+    fn test_checksum_engine_split_updates() {
+        let expected = calc_checksums(SAMPLES, ChecksumSelection::all(), false, false).unwrap();
+
+        // Split the input at every position, including ones that fall inside a sample.
+        for split in 0..SAMPLES.len() {
+            let mut engine = ChecksumEngine::new(ChecksumSelection::all(), SAMPLES.len(), false, false);
+            engine.update(&SAMPLES[..split]);
+            engine.update(&SAMPLES[split..]);
+
+            assert_eq!(expected, engine.finish().unwrap());
+        }
+
+        let mut engine = ChecksumEngine::new(ChecksumSelection::all(), SAMPLES.len(), false, false);
+        engine.update(&SAMPLES[..3]);
+        assert!(engine.finish().is_err());
+    }
+
+    #[test]
+    fn test_checksum_selection_from_str() {
+        let inputs_and_expected = vec![
+            ("ar", ChecksumSelection { ar: true, crc32: false, crc32_skip_zero: false, peak: false }),
+            ("crc32,peak", ChecksumSelection { ar: false, crc32: true, crc32_skip_zero: false, peak: true }),
+            ("ar, crc32, crc32-skip-zero, peak", ChecksumSelection::all()),
+            ("", ChecksumSelection::none()),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced: ChecksumSelection = input.parse().unwrap();
+            assert_eq!(expected, produced);
+        }
+
+        assert!("md5".parse::<ChecksumSelection>().is_err());
+
+        let produced = calc_checksums(SAMPLES, "peak".parse().unwrap(), false, false).unwrap();
+        assert_eq!(None, produced.ar);
+        assert_eq!(None, produced.copy.crc32);
+        assert_eq!(Some(32768), produced.copy.peak);
+    }
+}
@@ -0,0 +1,423 @@
+//! Loads default settings from a TOML configuration file.
+//! Every setting is optional, and command line flags take precedence over the file.
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use failure;
+use toml;
+
+use cache::BinCache;
+use decode::Decoder;
+use fetch::ACCURATERIP_DB_URL;
+use fetch::parse_host_list;
+use fetch::Fetcher;
+use fetch::ProxySettings;
+use fetch::ReqwestTransport;
+use fetch::RetryPolicy;
+use format::OutputFormat;
+use mirror::Mirror;
+use validate::AccuracyRequirement;
+
+const APP_DIR_NAME: &str = "ar-flac-rs";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub accuraterip_url: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            accuraterip_url: ACCURATERIP_DB_URL.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub timeout_secs: u64,
+    /// Number of times a request is retried after a connection error, server error or rate limiting.
+    pub max_retries: u32,
+    /// Delay before the first retry, which doubles with every further retry up to `max_backoff_ms`.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Smallest interval between two requests, to avoid overloading the database during batch runs.
+    pub min_request_interval_ms: u64,
+    /// Largest number of requests in flight at once when processing a whole library.
+    pub max_in_flight_requests: usize,
+    /// Proxy for every request. If unset, proxies are taken from the `http_proxy` family of environment variables.
+    pub proxy: Option<String>,
+    /// Comma-separated hosts to connect to directly, overriding the `no_proxy` environment variable.
+    pub no_proxy: Option<String>,
+    pub user_agent: String,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            timeout_secs: 30,
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30000,
+            min_request_interval_ms: 250,
+            max_in_flight_requests: 4,
+            proxy: None,
+            no_proxy: None,
+            user_agent: format!("ar-flac-rs/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries,
+            initial_backoff: Duration::from_millis(self.initial_backoff_ms),
+            max_backoff: Duration::from_millis(self.max_backoff_ms),
+            min_interval: Duration::from_millis(self.min_request_interval_ms),
+        }
+    }
+
+    pub fn proxy_settings(&self) -> ProxySettings {
+        let mut proxy_settings = match self.proxy {
+            Some(ref proxy) => ProxySettings::for_all(proxy),
+            None => ProxySettings::from_env(),
+        };
+
+        if let Some(ref no_proxy) = self.no_proxy {
+            proxy_settings.no_proxy = parse_host_list(no_proxy);
+        }
+
+        proxy_settings
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Directory holding cached dBAR files, `None` if no cache directory could be determined.
+    pub dir: Option<PathBuf>,
+    /// How long cached dBAR files are used before fetching them again, zero disables the cache.
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            dir: xdg_dir("XDG_CACHE_HOME", ".cache"),
+            ttl_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MirrorConfig {
+    /// Root directory of a local mirror of the database, laid out like the database itself.
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address and port the server listens on.
+    pub listen: String,
+    /// Whether rippers may submit results, which are merged into the mirror.
+    pub accept_submissions: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { listen: "127.0.0.1:8080".to_string(), accept_submissions: true }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct WatchConfig {
+    /// How long the files of an album must stay unchanged before it is considered complete.
+    pub settle_secs: u64,
+    /// Interval between scans of the inbox when file system notifications are unavailable or disabled.
+    pub poll_interval_secs: u64,
+    /// Whether to use file system notifications, which do not work on some network file systems.
+    pub notifications: bool,
+    /// If set, validated albums are moved into a subdirectory named after their verdict, instead of being tagged.
+    pub move_to: Option<PathBuf>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig { settle_secs: 30, poll_interval_secs: 10, notifications: true, move_to: None }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    /// Shell commands run after each album is verified.
+    pub post_verify: Vec<String>,
+    /// Whether a failing hook fails the verification of the album, instead of only being logged.
+    pub strict: bool,
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ReportConfig {
+    /// Secret key for signing reports, so that `check-report` also detects deliberate edits and not just bit rot.
+    pub key: Option<String>,
+}
+
+impl ReportConfig {
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_ref().map(String::as_str)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct ThresholdConfig {
+    /// Smallest total confidence for a matching track to count as accurate.
+    pub min_confidence: u32,
+    /// Smallest total confidence for a matching track to count as accurate with high confidence.
+    pub high_confidence: u32,
+    /// If set, validation fails unless every track is at least this accurate.
+    pub require: Option<AccuracyRequirement>,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        ThresholdConfig { min_confidence: 1, high_confidence: 10, require: None }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct OffsetConfig {
+    /// Largest offset in samples to search for when detecting the offset of a rip.
+    /// The default is five frames, the same amount AccurateRip leaves out at the edges of a disc.
+    pub max_offset: u32,
+}
+
+impl Default for OffsetConfig {
+    fn default() -> Self {
+        OffsetConfig { max_offset: 2940 }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig { format: OutputFormat::Text }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub network: NetworkConfig,
+    pub cache: CacheConfig,
+    pub mirror: MirrorConfig,
+    pub server: ServerConfig,
+    pub watch: WatchConfig,
+    pub hooks: HookConfig,
+    pub report: ReportConfig,
+    pub decoder: Decoder,
+    pub thresholds: ThresholdConfig,
+    pub offset: OffsetConfig,
+    pub output: OutputConfig,
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or from the default location if `path` is `None`.
+    /// A missing file at the default location is not an error, and results in the default configuration.
+    pub fn load(path: Option<&Path>) -> Result<Self, failure::Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path() {
+                Some(ref path) if path.is_file() => path.clone(),
+                _ => return Ok(Config::default()),
+            },
+        };
+
+        let mut text = String::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format_err!("unable to read config file {}: {}", path.display(), e))?;
+
+        Config::parse(&text).map_err(|e| format_err!("invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, failure::Error> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Returns the dBAR file cache, or `None` if caching is disabled.
+    pub fn bin_cache(&self) -> Option<BinCache> {
+        match self.cache.dir {
+            Some(ref dir) if self.cache.ttl_secs > 0 => Some(BinCache::new(dir, Duration::from_secs(self.cache.ttl_secs))),
+            _ => None,
+        }
+    }
+
+    /// Returns the local mirror of the database, failing if no mirror directory is configured.
+    pub fn mirror(&self) -> Result<Mirror, failure::Error> {
+        match self.mirror.dir {
+            Some(ref dir) => Ok(Mirror::new(dir)),
+            None => bail!("no mirror directory configured"),
+        }
+    }
+
+    /// Creates a fetcher for the configured AccurateRip database, using the configured cache and network settings.
+    pub fn fetcher(&self) -> Result<Fetcher<ReqwestTransport>, failure::Error> {
+        let transport = ReqwestTransport::new(
+            Duration::from_secs(self.network.timeout_secs),
+            self.network.proxy_settings(),
+            &self.network.user_agent,
+        )?;
+
+        Ok(Fetcher::new(transport, &self.database.accuraterip_url, self.bin_cache(), self.network.retry_policy()))
+    }
+}
+
+/// Returns the application directory within an XDG base directory,
+/// falling back to `fallback` within the home directory if the variable is unset.
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base_dir = match env::var_os(var) {
+        Some(ref dir) if Path::new(dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+
+    Some(base_dir.join(APP_DIR_NAME))
+}
+
+/// Returns the default location of the configuration file, e.g. `~/.config/ar-flac-rs/config.toml`.
+pub fn default_config_path() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use decode::DecoderKind;
+    use format::OutputFormat;
+    use validate::AccuracyRequirement;
+
+    use super::Config;
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+            # Only some settings are given, the rest keep their defaults.
+            [database]
+            accuraterip_url = "http://mirror.example.com/accuraterip"
+
+            [network]
+            timeout_secs = 5
+            max_retries = 0
+            max_in_flight_requests = 8
+            proxy = "http://proxy.example.com:3128"
+            no_proxy = "localhost,.example.org"
+
+            [cache]
+            dir = "/tmp/ar-cache"
+            ttl_secs = 0
+
+            [mirror]
+            dir = "/srv/accuraterip"
+
+            [server]
+            accept_submissions = false
+
+            [watch]
+            settle_secs = 120
+            notifications = false
+
+            [hooks]
+            post_verify = ["beet import -q \"$AR_FLAC_ALBUM_DIR\""]
+
+            [report]
+            key = "secret"
+
+            [decoder]
+            kind = "ffmpeg"
+            ffmpeg_path = "/opt/ffmpeg/bin/ffmpeg"
+
+            [thresholds]
+            min_confidence = 5
+            require = "high-confidence"
+
+            [offset]
+            max_offset = 1000
+
+            [output]
+            format = "json"
+        "#;
+
+        let produced = Config::parse(text).unwrap();
+        let default = Config::default();
+
+        assert_eq!("http://mirror.example.com/accuraterip", produced.database.accuraterip_url);
+        assert_eq!(5, produced.network.timeout_secs);
+        assert_eq!(0, produced.network.retry_policy().max_retries);
+        assert_eq!(default.network.min_request_interval_ms, produced.network.min_request_interval_ms);
+        assert_eq!(8, produced.network.max_in_flight_requests);
+        assert_eq!(default.network.user_agent, produced.network.user_agent);
+
+        let proxy_settings = produced.network.proxy_settings();
+        assert_eq!(Some("http://proxy.example.com:3128"), proxy_settings.proxy_for("https", "www.accuraterip.com"));
+        assert_eq!(None, proxy_settings.proxy_for("http", "mirror.example.org"));
+        assert_eq!(Some(PathBuf::from("/tmp/ar-cache")), produced.cache.dir);
+        assert_eq!(None, produced.bin_cache());
+        assert_eq!(Some(PathBuf::from("/srv/accuraterip")), produced.mirror.dir);
+        assert!(default.mirror().is_err());
+        assert_eq!(default.server.listen, produced.server.listen);
+        assert!(!produced.server.accept_submissions);
+        assert_eq!(120, produced.watch.settle_secs);
+        assert_eq!(default.watch.poll_interval_secs, produced.watch.poll_interval_secs);
+        assert!(!produced.watch.notifications);
+        assert_eq!(None, produced.watch.move_to);
+        assert_eq!(vec!["beet import -q \"$AR_FLAC_ALBUM_DIR\"".to_string()], produced.hooks.post_verify);
+        assert!(!produced.hooks.strict);
+        assert_eq!(Some("secret"), produced.report.key());
+        assert_eq!(None, default.report.key());
+        assert_eq!(DecoderKind::Ffmpeg, produced.decoder.kind);
+        assert_eq!(PathBuf::from("/opt/ffmpeg/bin/ffmpeg"), produced.decoder.ffmpeg_path);
+        assert_eq!(default.decoder.flac_path, produced.decoder.flac_path);
+        assert_eq!(5, produced.thresholds.min_confidence);
+        assert_eq!(default.thresholds.high_confidence, produced.thresholds.high_confidence);
+        assert_eq!(Some(AccuracyRequirement::HighConfidence), produced.thresholds.require);
+        assert_eq!(1000, produced.offset.max_offset);
+        assert_eq!(OutputFormat::Json, produced.output.format);
+
+        assert_eq!(default, Config::parse("").unwrap());
+    }
+
+    #[test]
+    fn test_parse_config_invalid() {
+        let inputs = vec![
+            "[decoder]\nkind = \"sox\"",
+            "[thresholds]\nmin_confidence = \"high\"",
+            "[output]\nformat = \"xml\"",
+            "[thresholds]\nrequire = \"perfect\"",
+        ];
+
+        for input in inputs {
+            assert!(Config::parse(input).is_err());
+        }
+    }
+}
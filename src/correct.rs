@@ -0,0 +1,440 @@
+//! Corrects rips that were made with a missing or wrong read offset.
+//! The audio of the whole disc is shifted by a number of samples, moving audio across track boundaries,
+//! and re-encoded into a new set of FLAC files.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use offset::DiscInfo;
+use fetch::unpack_ar_bin;
+use fetch::BinSource;
+use fetch::TrackResult;
+use crc::CRC;
+use checksum::ChecksumSelection;
+use decode::decode_and_verify_flac_file;
+use decode::get_decoded_len;
+use decode::encode_flac_file;
+use decode::Md5Check;
+use config::Config;
+use tag::copy_tags;
+use validate::identify_album;
+use validate::validate;
+use validate::ValidationReport;
+use progress::Phase;
+use progress::Progress;
+use progress::ProgressListener;
+
+/// A shift of the disc audio that makes local tracks match the database.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OffsetMatch {
+    /// Sample `i` of the corrected disc is sample `i + offset` of the rip.
+    pub offset: i32,
+    /// Number of tracks that match a pressing at this offset.
+    pub num_tracks: usize,
+    /// Sum of the confidence of every match at this offset.
+    pub confidence: u32,
+}
+
+/// The audio of every file of a disc, joined into one continuous run of samples.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DiscAudio {
+    pub bytes: Vec<u8>,
+    /// Number of samples in each file, in disc order.
+    pub file_lengths: Vec<usize>,
+}
+
+impl DiscAudio {
+    pub fn new(files_audio: Vec<Vec<u8>>) -> Result<Self, failure::Error> {
+        let mut bytes = vec![];
+        let mut file_lengths = vec![];
+
+        for file_audio in files_audio {
+            if file_audio.len() % 4 != 0 {
+                bail!("track audio is not a whole number of samples");
+            }
+
+            file_lengths.push(file_audio.len() / 4);
+            bytes.extend(file_audio);
+        }
+
+        Ok(DiscAudio { bytes, file_lengths })
+    }
+
+    /// Returns the index of the first sample of a file within the disc.
+    pub fn file_start(&self, file_index: usize) -> usize {
+        self.file_lengths[..file_index].iter().sum()
+    }
+
+    /// Splits the disc audio back into the audio of each file.
+    pub fn files(&self) -> Vec<&[u8]> {
+        let mut files = vec![];
+        let mut start = 0;
+
+        for &file_length in &self.file_lengths {
+            files.push(&self.bytes[start * 4..(start + file_length) * 4]);
+            start += file_length;
+        }
+
+        files
+    }
+
+    /// Shifts the disc audio by `offset` samples, keeping every file the same length.
+    /// Samples that are shifted past the edges of the disc are lost, and the gap left behind is filled with silence.
+    pub fn shifted(&self, offset: i32) -> Self {
+        let len = self.bytes.len();
+        let shift_len = (offset.abs() as usize * 4).min(len);
+        let mut bytes = vec![0u8; len];
+
+        if offset >= 0 {
+            bytes[..len - shift_len].copy_from_slice(&self.bytes[shift_len..]);
+        }
+        else {
+            bytes[shift_len..].copy_from_slice(&self.bytes[..len - shift_len]);
+        }
+
+        DiscAudio { bytes, file_lengths: self.file_lengths.clone() }
+    }
+}
+
+/// Reads a sample from disc audio, treating positions beyond the edges of the disc as silence.
+fn sample_at(bytes: &[u8], index: i64) -> u32 {
+    if index < 0 || index as usize >= bytes.len() / 4 {
+        0
+    }
+    else {
+        LittleEndian::read_u32(&bytes[index as usize * 4..])
+    }
+}
+
+/// Calculates the v1 CRC of a track that is neither the first nor the last on the disc,
+/// at every offset from `-max_offset` to `max_offset`, in that order.
+/// Shifting by one sample changes the CRC by an amount that only depends on the sum of the samples,
+/// so each offset after the first costs a constant amount of work.
+pub fn calc_sliding_v1_crcs(disc_bytes: &[u8], start: usize, num_samples: usize, max_offset: u32) -> Vec<CRC> {
+    let start = start as i64;
+    let n = num_samples as i64;
+    let max_offset = max_offset as i64;
+
+    let mut crc = 0u32;
+    let mut sum = 0u32;
+
+    for j in 0..n {
+        let sample = sample_at(disc_bytes, start - max_offset + j);
+        crc = crc.wrapping_add(((j + 1) as u32).wrapping_mul(sample));
+        sum = sum.wrapping_add(sample);
+    }
+
+    let mut crcs = Vec::with_capacity(2 * max_offset as usize + 1);
+
+    for offset in -max_offset..max_offset + 1 {
+        crcs.push(crc);
+
+        let outgoing = sample_at(disc_bytes, start + offset);
+        let incoming = sample_at(disc_bytes, start + offset + n);
+
+        crc = crc.wrapping_sub(sum).wrapping_add((n as u32).wrapping_mul(incoming));
+        sum = sum.wrapping_sub(outgoing).wrapping_add(incoming);
+    }
+
+    crcs
+}
+
+/// Searches for the offset at which the most tracks match a pressing in the database.
+/// `first_track` is the index of the file holding track 1, which is 1 if the disc has an HTOA file.
+/// Only v1 CRCs of tracks other than the first and last are checked, since those cover every sample of the track.
+pub fn detect_offset(
+    disc: &DiscAudio,
+    first_track: usize,
+    disc_info: &DiscInfo,
+    bin_results: &[(DiscInfo, Vec<TrackResult>)],
+    max_offset: u32,
+) -> Option<OffsetMatch>
+{
+    let num_tracks = disc.file_lengths.len() - first_track;
+    let mut totals: HashMap<i32, (usize, u32)> = HashMap::new();
+
+    for track_index in 1..num_tracks.saturating_sub(1) {
+        let mut expected: HashMap<CRC, u32> = HashMap::new();
+
+        for &(ref bin_disc_info, ref track_results) in bin_results {
+            if bin_disc_info != disc_info {
+                continue;
+            }
+
+            if let Some(track_result) = track_results.get(track_index) {
+                if track_result.confidence > 0 {
+                    *expected.entry(track_result.crc).or_insert(0) += track_result.confidence as u32;
+                }
+            }
+        }
+
+        if expected.is_empty() {
+            continue;
+        }
+
+        let file_index = first_track + track_index;
+        let crcs = calc_sliding_v1_crcs(&disc.bytes, disc.file_start(file_index), disc.file_lengths[file_index], max_offset);
+
+        for (i, crc) in crcs.iter().enumerate() {
+            if let Some(&confidence) = expected.get(crc) {
+                let total = totals.entry(i as i32 - max_offset as i32).or_insert((0, 0));
+                total.0 += 1;
+                total.1 += confidence;
+            }
+        }
+    }
+
+    // Prefer smaller shifts when several offsets are equally good, e.g. for silent tracks.
+    totals.into_iter()
+        .map(|(offset, (num_tracks, confidence))| OffsetMatch { offset, num_tracks, confidence })
+        .max_by_key(|m| (m.num_tracks, m.confidence, Reverse(m.offset.abs()), m.offset))
+}
+
+/// Canonicalizes a directory that may not exist yet, by canonicalizing its nearest existing ancestor.
+fn resolve_dir(dir: &Path) -> Result<PathBuf, failure::Error> {
+    if dir.exists() {
+        return Ok(fs::canonicalize(dir)?);
+    }
+
+    let dir_name = dir.file_name().ok_or_else(|| format_err!("invalid directory path: {}", dir.display()))?;
+    let parent = match dir.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+
+    Ok(resolve_dir(parent)?.join(dir_name))
+}
+
+/// The outcome of correcting the offset of a rip.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Correction {
+    pub offset: i32,
+    pub files: Vec<PathBuf>,
+    /// Validation report of the corrected files.
+    pub report: ValidationReport,
+}
+
+/// Writes a copy of the FLAC files in `flac_dir` into `output_dir`, shifted by `offset` samples,
+/// keeping the file names and tags of the originals, and then verifies the new files against dBAR files from `source`.
+/// If `offset` is `None`, it is detected by searching up to the configured maximum offset in either direction.
+/// The whole disc is decoded into memory, since samples move between neighbouring tracks.
+pub fn correct<P: AsRef<Path>, Q: AsRef<Path>>(
+    flac_dir: P,
+    output_dir: Q,
+    offset: Option<i32>,
+    selection: ChecksumSelection,
+    config: &Config,
+    source: &BinSource,
+    progress: &ProgressListener,
+) -> Result<Correction, failure::Error>
+{
+    let output_dir = output_dir.as_ref();
+
+    if fs::canonicalize(flac_dir.as_ref())? == resolve_dir(output_dir)? {
+        bail!("output directory must differ from the input directory");
+    }
+
+    progress.on_progress(&Progress::new(Phase::Identify));
+    let album = identify_album(flac_dir.as_ref())?;
+
+    let first_track = if album.htoa_file.is_some() { 1 } else { 0 };
+    let src_files: Vec<PathBuf> = album.htoa_file.iter().chain(&album.track_files).cloned().collect();
+
+    let decoded_lens = src_files.iter().map(get_decoded_len).collect::<Result<Vec<_>, _>>()?;
+    let mut state = Progress {
+        num_tracks: album.track_files.len(),
+        bytes_total: decoded_lens.iter().sum(),
+        ..Progress::new(Phase::Decode)
+    };
+
+    let mut files_audio = vec![];
+
+    for (file_index, (src_file, decoded_len)) in src_files.iter().zip(decoded_lens).enumerate() {
+        let bytes_before = state.bytes_done;
+
+        state.track = Some(file_index + 1 - first_track);
+        progress.on_progress(&state);
+
+        let (samples, md5_check) = decode_and_verify_flac_file(&config.decoder, src_file, &mut |bytes_read| {
+            state.bytes_done = bytes_before + bytes_read;
+            progress.on_progress(&state);
+        })?;
+
+        state.bytes_done = bytes_before + decoded_len;
+
+        if md5_check == Md5Check::Mismatch {
+            bail!("{} is corrupted, and should be re-ripped instead", src_file.display());
+        }
+
+        files_audio.push(samples);
+    }
+
+    let disc = DiscAudio::new(files_audio)?;
+
+    let offset = match offset {
+        Some(offset) => offset,
+        None => {
+            let max_offset = config.offset.max_offset;
+
+            progress.on_progress(&Progress { phase: Phase::Fetch, track: None, ..state });
+            let bin_results = match source.get_ar_bin(&album.disc_info)? {
+                Some(ar_bin_data) => unpack_ar_bin(&ar_bin_data)?,
+                None => bail!("disc not present in AccurateRip database, so its offset cannot be detected"),
+            };
+
+            match detect_offset(&disc, first_track, &album.disc_info, &bin_results, max_offset) {
+                Some(offset_match) => offset_match.offset,
+                None => bail!("no offset within {} samples matches the database", max_offset),
+            }
+        },
+    };
+
+    fs::create_dir_all(output_dir)?;
+
+    let corrected = disc.shifted(offset);
+    let mut files = vec![];
+
+    for (src_file, file_audio) in src_files.iter().zip(corrected.files()) {
+        let file_name = src_file.file_name().ok_or_else(|| format_err!("invalid file path: {}", src_file.display()))?;
+        let dst_file = output_dir.join(file_name);
+
+        encode_flac_file(&config.decoder, file_audio, &dst_file)?;
+        copy_tags(src_file, &dst_file)?;
+
+        files.push(dst_file);
+    }
+
+    let mut report = validate(output_dir, selection, config, source, progress)?;
+
+    for track in &mut report.tracks {
+        track.offset = offset;
+    }
+
+    Ok(Correction { offset, files, report })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+
+    use self::tempfile::tempdir;
+
+    use offset::DiscInfo;
+    use offset::SAMPLE_DISC_INFO;
+    use fetch::TrackResult;
+    use crc::calc_ar_crcs;
+
+    use super::calc_sliding_v1_crcs;
+    use super::detect_offset;
+    use super::resolve_dir;
+    use super::DiscAudio;
+
+    const DISC_INFO: DiscInfo = DiscInfo { num_tracks: 4, ..SAMPLE_DISC_INFO };
+
+    fn random_disc(file_lengths: &[usize], seed: u32) -> DiscAudio {
+        let mut state = seed;
+
+        let files_audio = file_lengths.iter().map(|&len| {
+            (0..len * 4).map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            }).collect()
+        }).collect();
+
+        DiscAudio::new(files_audio).unwrap()
+    }
+
+    #[test]
+    fn test_disc_audio_shifted() {
+        let disc = DiscAudio::new(vec![vec![1, 0, 1, 0, 2, 0, 2, 0], vec![3, 0, 3, 0]]).unwrap();
+
+        let inputs_and_expected = vec![
+            (0, vec![1, 0, 1, 0, 2, 0, 2, 0, 3, 0, 3, 0]),
+            (1, vec![2, 0, 2, 0, 3, 0, 3, 0, 0, 0, 0, 0]),
+            (-2, vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0]),
+            (5, vec![0; 12]),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = disc.shifted(input);
+            assert_eq!(expected, produced.bytes);
+            assert_eq!(vec![2, 1], produced.file_lengths);
+        }
+
+        assert_eq!(vec![&[1, 0, 1, 0, 2, 0, 2, 0][..], &[3, 0, 3, 0][..]], disc.files());
+        assert!(DiscAudio::new(vec![vec![0, 1, 2]]).is_err());
+    }
+
+    #[test]
+    fn test_calc_sliding_v1_crcs() {
+        let disc = random_disc(&[300, 500, 300], 3);
+        let max_offset = 350;
+
+        let produced = calc_sliding_v1_crcs(&disc.bytes, disc.file_start(1), disc.file_lengths[1], max_offset);
+        assert_eq!(2 * max_offset as usize + 1, produced.len());
+
+        for (i, produced_crc) in produced.into_iter().enumerate() {
+            let offset = i as i32 - max_offset as i32;
+            let shifted = disc.shifted(offset);
+            let expected = calc_ar_crcs(shifted.files()[1], false, false).unwrap();
+
+            assert_eq!(expected.v1, produced_crc);
+        }
+    }
+
+    #[test]
+    fn test_detect_offset() {
+        let reference = random_disc(&[1000, 800, 900, 700], 5);
+
+        let track_results = reference.files().iter().map(|track_audio| {
+            TrackResult { confidence: 10, crc: calc_ar_crcs(track_audio, false, false).unwrap().v1 }
+        }).collect();
+        let bin_results = vec![(DISC_INFO, track_results)];
+
+        for &offset in &[0, 7, -102, 667] {
+            // A rip that reads `offset` samples too early or late.
+            let rip = reference.shifted(-offset);
+
+            let produced = detect_offset(&rip, 0, &DISC_INFO, &bin_results, 700).unwrap();
+            assert_eq!(offset, produced.offset);
+            assert_eq!(2, produced.num_tracks);
+            assert_eq!(20, produced.confidence);
+
+            // Only the samples lost past the disc edges differ after correcting.
+            let corrected = rip.shifted(produced.offset);
+            assert_eq!(reference.files()[1..3], corrected.files()[1..3]);
+        }
+
+        assert_eq!(None, detect_offset(&reference, 0, &DISC_INFO, &bin_results[..0], 700));
+    }
+
+    #[test]
+    fn test_resolve_dir() {
+        let temp_dir = tempdir().unwrap();
+        let root = fs::canonicalize(temp_dir.path()).unwrap();
+
+        let inputs_and_expected = vec![
+            (temp_dir.path().to_path_buf(), root.clone()),
+            (temp_dir.path().join("."), root.clone()),
+            (temp_dir.path().join("out"), root.join("out")),
+            (temp_dir.path().join("out").join("nested"), root.join("out").join("nested")),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = resolve_dir(&input).unwrap();
+            assert_eq!(expected, produced);
+        }
+
+        // Nothing is created while resolving.
+        assert!(!temp_dir.path().join("out").exists());
+    }
+}
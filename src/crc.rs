@@ -1,69 +1,183 @@
 //! Calculates AccurateRip (v1 and v2) CRCs for local audio files.
 
-use std::io;
-use std::io::Read;
-
 use failure;
+use byteorder::ByteOrder;
 use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
 use rayon::prelude::*;
 
 // Note that 'frame' == 'sector'.
 const BYTES_PER_FRAME: usize = 2352;
 // const WORDS_PER_FRAME: u32 = BYTES_PER_FRAME / 4;
 
+/// Number of samples processed by a single rayon task.
+pub const BLOCK_SAMPLES: usize = 16384;
+
+/// Number of samples converted from bytes at a time, small enough to stay in L1 cache.
+const CONVERT_SAMPLES: usize = 1024;
+
+/// Number of independent accumulators in the inner loop, sized so that it compiles to SIMD multiplies.
+const LANES: usize = 8;
+
 pub type CRC = u32;
 
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 pub struct CRCs {
-    v1: CRC,
-    v2: CRC,
+    pub v1: CRC,
+    pub v2: CRC,
 }
 
-pub fn calc_ar_crcs(track_audio_bytes: &[u8], is_first: bool, is_last: bool) -> Result<CRCs, failure::Error> {
-    let head_offset = if is_first { BYTES_PER_FRAME * 5 } else { 0 };
-    let tail_offset = track_audio_bytes.len() - (if is_last { BYTES_PER_FRAME * 5 } else { 0 });
+impl CRCs {
+    /// Combines the partial CRCs of two disjoint ranges of samples.
+    /// Both CRCs are plain wrapping sums, so partial results can be combined in any order.
+    pub fn combine(self, other: CRCs) -> CRCs {
+        CRCs { v1: self.v1.wrapping_add(other.v1), v2: self.v2.wrapping_add(other.v2) }
+    }
+}
 
-    let result = &track_audio_bytes.par_chunks(4).zip(1usize..usize::max_value()).map(
-        |(chunk, multi)| {
-            let mut cursor = io::Cursor::new(chunk);
-            let sample = cursor.read_u32::<LittleEndian>()?;
+/// Number of samples skipped at the start of the first track and the end of the last track of a disc,
+/// where drive offsets make reads unreliable.
+const SKIPPED_EDGE_SAMPLES: usize = 5 * BYTES_PER_FRAME / 4;
 
-            if multi >= head_offset && multi <= tail_offset {
-                // Version 1 CRC.
-                let ar_crc_v1 = (multi as u32).wrapping_mul(sample);
+/// Returns the inclusive bounds that the 1-based sample multipliers of a track must lie within to contribute to its CRCs.
+/// `track_len` is the length of the track in bytes, while both bounds are in samples.
+/// The first track starts at the last sample of its fifth frame, and the last track ends five frames before its end.
+/// A last track shorter than five frames has a tail bound of zero, so none of its samples contribute.
+pub fn ar_window(track_len: usize, is_first: bool, is_last: bool) -> (usize, usize) {
+    let head_offset = if is_first { SKIPPED_EDGE_SAMPLES } else { 0 };
+    let tail_offset = (track_len / 4).saturating_sub(if is_last { SKIPPED_EDGE_SAMPLES } else { 0 });
 
-                // Version 2 CRC.
-                let calc: u64 = sample as u64 * multi as u64;
-                let calc_lo: u32 = (calc & 0xFFFFFFFF) as u32;
-                let calc_hi: u32 = (calc / 0x100000000) as u32;
+    (head_offset, tail_offset)
+}
 
-                let ar_crc_v2 = calc_hi.wrapping_add(calc_lo);
+/// Calculates the partial CRCs of a run of samples, whose first sample has the multiplier `first_multi`.
+/// Samples with a multiplier outside of `window` do not contribute.
+pub fn calc_partial_ar_crcs(sample_bytes: &[u8], first_multi: usize, window: (usize, usize)) -> CRCs {
+    let num_samples = sample_bytes.len() / 4;
 
-                Ok((ar_crc_v1, ar_crc_v2))
-            }
-            else {
-                Ok((0, 0))
+    if num_samples == 0 {
+        return CRCs { v1: 0, v2: 0 };
+    }
+
+    // Clamp to the window up front, so the inner loop does not need to branch.
+    let (head_offset, tail_offset) = window;
+    let lo_multi = first_multi.max(head_offset);
+    let hi_multi = (first_multi + num_samples - 1).min(tail_offset);
+
+    if lo_multi > hi_multi {
+        return CRCs { v1: 0, v2: 0 };
+    }
+
+    let sample_bytes = &sample_bytes[(lo_multi - first_multi) * 4..(hi_multi - first_multi + 1) * 4];
+
+    let mut samples = [0u32; CONVERT_SAMPLES];
+    let mut lo_sums = [0u32; LANES];
+    let mut hi_sums = [0u32; LANES];
+    let mut multi = lo_multi as u64;
+
+    for byte_block in sample_bytes.chunks(CONVERT_SAMPLES * 4) {
+        let samples = &mut samples[..byte_block.len() / 4];
+        LittleEndian::read_u32_into(byte_block, samples);
+
+        let whole_len = samples.len() / LANES * LANES;
+        let (whole, rest) = samples.split_at(whole_len);
+
+        for lanes in whole.chunks(LANES) {
+            for lane in 0..LANES {
+                let calc = lanes[lane] as u64 * (multi + lane as u64);
+                lo_sums[lane] = lo_sums[lane].wrapping_add(calc as u32);
+                hi_sums[lane] = hi_sums[lane].wrapping_add((calc >> 32) as u32);
             }
+
+            multi += LANES as u64;
         }
-    ).try_fold_with((0u32, 0u32), |(ar_crc_v1_a, ar_crc_v2_a): (u32, u32), res_ar_crcs_b: Result<(u32, u32), failure::Error>| {
-        res_ar_crcs_b.map(|(ar_crc_v1_b, ar_crc_v2_b)| { (ar_crc_v1_a.wrapping_add(ar_crc_v1_b), ar_crc_v2_a.wrapping_add(ar_crc_v2_b)) })
-    }).try_reduce(|| (0, 0), |(v1_a, v2_a), (v1_b, v2_b)| {
-        Ok((v1_a.wrapping_add(v1_b), v2_a.wrapping_add(v2_b)))
-    })?;
 
-    Ok(CRCs { v1: result.0, v2: result.1 })
+        for &sample in rest {
+            let calc = sample as u64 * multi;
+            lo_sums[0] = lo_sums[0].wrapping_add(calc as u32);
+            hi_sums[0] = hi_sums[0].wrapping_add((calc >> 32) as u32);
+
+            multi += 1;
+        }
+    }
+
+    let lo_sum = lo_sums.iter().fold(0u32, |a, &b| a.wrapping_add(b));
+    let hi_sum = hi_sums.iter().fold(0u32, |a, &b| a.wrapping_add(b));
+
+    // The v1 CRC is the sum of the low halves of each product, the v2 CRC also adds the high halves.
+    CRCs { v1: lo_sum, v2: lo_sum.wrapping_add(hi_sum) }
+}
+
+pub fn calc_ar_crcs(track_audio_bytes: &[u8], is_first: bool, is_last: bool) -> Result<CRCs, failure::Error> {
+    if track_audio_bytes.len() % 4 != 0 {
+        bail!("track audio is not a whole number of samples");
+    }
+
+    let window = ar_window(track_audio_bytes.len(), is_first, is_last);
+    trace!("calculating AR CRCs over {} samples, window {:?}", track_audio_bytes.len() / 4, window);
+
+    let crcs = track_audio_bytes.par_chunks(BLOCK_SAMPLES * 4).enumerate()
+        .map(|(block_index, block)| calc_partial_ar_crcs(block, block_index * BLOCK_SAMPLES + 1, window))
+        .reduce(|| CRCs { v1: 0, v2: 0 }, CRCs::combine);
+
+    Ok(crcs)
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate test;
+
+    use std::io;
     use std::path::PathBuf;
 
+    use byteorder::LittleEndian;
+    use byteorder::ReadBytesExt;
+    use failure;
+    use rayon::prelude::*;
+    use self::test::Bencher;
+
     use super::calc_ar_crcs;
+    use super::calc_partial_ar_crcs;
+    use super::ar_window;
     use super::CRCs;
+    use super::BLOCK_SAMPLES;
 
     use test_util::load_bytes;
 
+    /// Straightforward per-sample implementation, used as the reference that `calc_ar_crcs` must match exactly.
+    fn calc_ar_crcs_scalar(track_audio_bytes: &[u8], is_first: bool, is_last: bool) -> Result<CRCs, failure::Error> {
+        let (head_offset, tail_offset) = ar_window(track_audio_bytes.len(), is_first, is_last);
+
+        let result = &track_audio_bytes.par_chunks(4).zip(1usize..usize::max_value()).map(
+            |(chunk, multi)| {
+                let mut cursor = io::Cursor::new(chunk);
+                let sample = cursor.read_u32::<LittleEndian>()?;
+
+                if multi >= head_offset && multi <= tail_offset {
+                    // Version 1 CRC.
+                    let ar_crc_v1 = (multi as u32).wrapping_mul(sample);
+
+                    // Version 2 CRC.
+                    let calc: u64 = sample as u64 * multi as u64;
+                    let calc_lo: u32 = (calc & 0xFFFFFFFF) as u32;
+                    let calc_hi: u32 = (calc / 0x100000000) as u32;
+
+                    let ar_crc_v2 = calc_hi.wrapping_add(calc_lo);
+
+                    Ok((ar_crc_v1, ar_crc_v2))
+                }
+                else {
+                    Ok((0, 0))
+                }
+            }
+        ).try_fold_with((0u32, 0u32), |(ar_crc_v1_a, ar_crc_v2_a): (u32, u32), res_ar_crcs_b: Result<(u32, u32), failure::Error>| {
+            res_ar_crcs_b.map(|(ar_crc_v1_b, ar_crc_v2_b)| { (ar_crc_v1_a.wrapping_add(ar_crc_v1_b), ar_crc_v2_a.wrapping_add(ar_crc_v2_b)) })
+        }).try_reduce(|| (0, 0), |(v1_a, v2_a), (v1_b, v2_b)| {
+            Ok((v1_a.wrapping_add(v1_b), v2_a.wrapping_add(v2_b)))
+        })?;
+
+        Ok(CRCs { v1: result.0, v2: result.1 })
+    }
+
     #[test]
     #[ignore("long test")]
     fn test_calc_ar_crcs() {
@@ -89,4 +203,90 @@ mod tests {
             assert_eq!(expected, produced);
         }
     }
+
+    /// Generates deterministic pseudo-random audio, so that tests and benchmarks do not need fixtures.
+    fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_calc_ar_crcs_matches_scalar() {
+        let lengths = vec![
+            // Shorter than the skipped region at the start of the first track.
+            4 * 100,
+            // Exactly one rayon block, and a block plus a partial lane.
+            4 * BLOCK_SAMPLES,
+            4 * (BLOCK_SAMPLES + 3),
+            // Long enough to cover several blocks and both skipped regions.
+            4 * (3 * BLOCK_SAMPLES + 588 * 7 + 5),
+        ];
+
+        for (seed, len) in lengths.into_iter().enumerate() {
+            let bytes = random_bytes(len, seed as u32);
+
+            for &(is_first, is_last) in &[(false, false), (true, false), (false, true), (true, true)] {
+                let expected = calc_ar_crcs_scalar(&bytes, is_first, is_last).unwrap();
+                let produced = calc_ar_crcs(&bytes, is_first, is_last).unwrap();
+                assert_eq!(expected, produced);
+            }
+        }
+    }
+
+    #[test]
+    fn test_calc_partial_ar_crcs_combine() {
+        let bytes = random_bytes(4 * 10000, 7);
+        let window = ar_window(bytes.len(), true, true);
+
+        let expected = calc_ar_crcs_scalar(&bytes, true, true).unwrap();
+
+        // Splitting at arbitrary sample boundaries must give the same result once combined.
+        for &split in &[0, 1, 2939, 2940, 5000, 9999, 10000] {
+            let head = calc_partial_ar_crcs(&bytes[..split * 4], 1, window);
+            let tail = calc_partial_ar_crcs(&bytes[split * 4..], split + 1, window);
+            assert_eq!(expected, head.combine(tail));
+        }
+    }
+
+    #[test]
+    fn test_ar_window() {
+        let inputs_and_expected = vec![
+            ((4 * 10000, false, false), (0, 10000)),
+            ((4 * 10000, true, false), (2940, 10000)),
+            ((4 * 10000, false, true), (0, 7060)),
+            ((4 * 10000, true, true), (2940, 7060)),
+            // Last tracks shorter than the skipped region at the end do not underflow.
+            ((4 * 100, false, true), (0, 0)),
+            ((4 * 100, true, true), (2940, 0)),
+        ];
+
+        for ((track_len, is_first, is_last), expected) in inputs_and_expected {
+            let produced = ar_window(track_len, is_first, is_last);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_calc_ar_crcs_partial_sample() {
+        assert!(calc_ar_crcs(&[0, 1, 2, 3, 4], false, false).is_err());
+    }
+
+    #[bench]
+    fn bench_calc_ar_crcs(b: &mut Bencher) {
+        // Roughly one minute of audio.
+        let bytes = random_bytes(44100 * 4 * 60, 1);
+        b.bytes = bytes.len() as u64;
+        b.iter(|| calc_ar_crcs(&bytes, true, true).unwrap());
+    }
+
+    #[bench]
+    fn bench_calc_ar_crcs_scalar(b: &mut Bencher) {
+        let bytes = random_bytes(44100 * 4 * 60, 1);
+        b.bytes = bytes.len() as u64;
+        b.iter(|| calc_ar_crcs_scalar(&bytes, true, true).unwrap());
+    }
 }
@@ -1,31 +1,253 @@
 //! Handles decoding encoding audio formats into raw samples.
 
+use std::str::FromStr;
 use std::process::Command;
 use std::process::Stdio;
 use std::path::Path;
+use std::path::PathBuf;
 use std::io::Read;
+use std::io::Write;
 
 use failure;
+use md5;
+use metaflac::Tag;
+use metaflac::block::{Block, BlockType};
 
-pub fn decode_flac_file<P: AsRef<Path>>(flac_path: P) -> Result<Vec<u8>, failure::Error> {
-    let process = Command::new("flac")
-                          .args(&[
-                              "-d",
-                              "-c",
-                              "-f",
-                              "--force-raw-format",
-                              "--totally-silent",
-                              "--endian=little",
-                              "--sign=signed",
-                          ])
-                          .arg(flac_path.as_ref())
-                          .stdout(Stdio::piped())
-                          .spawn()?;
+/// Outcome of comparing decoded audio against the MD5 signature stored in a FLAC file.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Md5Check {
+    Match,
+    Mismatch,
+    /// The encoder did not store a signature, which is indicated by an all-zero MD5.
+    Unavailable,
+}
+
+/// External program used to decode audio files into raw samples.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecoderKind {
+    Flac,
+    Ffmpeg,
+}
+
+impl FromStr for DecoderKind {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flac" => Ok(DecoderKind::Flac),
+            "ffmpeg" => Ok(DecoderKind::Ffmpeg),
+            _ => bail!("unknown decoder: {}", s),
+        }
+    }
+}
+
+/// Which external program decodes audio, and where to find the external programs.
+/// Encoding always uses the `flac` binary.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(default)]
+pub struct Decoder {
+    pub kind: DecoderKind,
+    pub flac_path: PathBuf,
+    pub ffmpeg_path: PathBuf,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            kind: DecoderKind::Flac,
+            flac_path: PathBuf::from("flac"),
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+        }
+    }
+}
+
+/// Size of one stereo sample of 16-bit CD audio.
+const BYTES_PER_SAMPLE: u64 = 4;
+
+/// Size of the pieces in which decoded audio is read, which is how often decoding progress is reported.
+const READ_CHUNK_LEN: u64 = 1024 * 1024;
+
+/// Decodes a FLAC file into raw samples.
+/// `on_read` is called with the number of bytes decoded so far, each time more audio has been read.
+pub fn decode_flac_file<P: AsRef<Path>>(
+    decoder: &Decoder,
+    flac_path: P,
+    on_read: &mut FnMut(u64),
+) -> Result<Vec<u8>, failure::Error> {
+    let mut command = match decoder.kind {
+        DecoderKind::Flac => {
+            let mut command = Command::new(&decoder.flac_path);
+            command.args(&[
+                       "-d",
+                       "-c",
+                       "-f",
+                       "--force-raw-format",
+                       "--totally-silent",
+                       "--endian=little",
+                       "--sign=signed",
+                   ])
+                   .arg(flac_path.as_ref());
+            command
+        },
+        DecoderKind::Ffmpeg => {
+            let mut command = Command::new(&decoder.ffmpeg_path);
+            command.args(&["-v", "error", "-i"])
+                   .arg(flac_path.as_ref())
+                   .args(&["-f", "s16le", "-acodec", "pcm_s16le", "-"]);
+            command
+        },
+    };
+
+    debug!("running {:?}", command);
+    let process = command.stdout(Stdio::piped()).spawn()?;
 
     let mut output = vec![];
 
     // TODO: Might be able to return the stdout object directly.
-    process.stdout.unwrap().read_to_end(&mut output)?;
+    let mut stdout = process.stdout.unwrap();
+
+    while (&mut stdout).take(READ_CHUNK_LEN).read_to_end(&mut output)? > 0 {
+        on_read(output.len() as u64);
+    }
 
     Ok(output)
 }
+
+/// Encodes raw CD audio (signed, little-endian, 16-bit stereo at 44.1 kHz) into a new FLAC file.
+/// The encoder calculates a fresh MD5 signature for the new audio.
+pub fn encode_flac_file<P: AsRef<Path>>(decoder: &Decoder, samples: &[u8], flac_path: P) -> Result<(), failure::Error> {
+    debug!("encoding {} with {}", flac_path.as_ref().display(), decoder.flac_path.display());
+
+    let mut process = Command::new(&decoder.flac_path)
+                              .args(&[
+                                  "-f",
+                                  "--force-raw-format",
+                                  "--totally-silent",
+                                  "--endian=little",
+                                  "--sign=signed",
+                                  "--channels=2",
+                                  "--bps=16",
+                                  "--sample-rate=44100",
+                                  "-o",
+                              ])
+                              .arg(flac_path.as_ref())
+                              .arg("-")
+                              .stdin(Stdio::piped())
+                              .spawn()?;
+
+    {
+        let stdin = process.stdin.as_mut().ok_or_else(|| format_err!("could not open encoder input"))?;
+        stdin.write_all(samples)?;
+    }
+
+    let status = process.wait()?;
+
+    if !status.success() {
+        bail!("encoding {} failed: {}", flac_path.as_ref().display(), status);
+    }
+
+    Ok(())
+}
+
+/// Reads the MD5 signature of the unencoded audio from the STREAMINFO block of a FLAC file.
+/// Returns `None` if the signature is unset.
+pub fn get_stream_md5<P: AsRef<Path>>(flac_path: P) -> Result<Option<Vec<u8>>, failure::Error> {
+    let flac_tag = Tag::read_from_path(flac_path)?;
+
+    let info_blocks = flac_tag.get_blocks(BlockType::StreamInfo);
+
+    if let Some(Block::StreamInfo(stream_info_block)) = info_blocks.first() {
+        if stream_info_block.md5.iter().all(|&b| b == 0) {
+            Ok(None)
+        }
+        else {
+            Ok(Some(stream_info_block.md5.clone()))
+        }
+    }
+    else {
+        bail!("no stream info block found");
+    }
+}
+
+/// Calculates the size of the decoded audio of a FLAC file from its STREAMINFO block.
+pub fn get_decoded_len<P: AsRef<Path>>(flac_path: P) -> Result<u64, failure::Error> {
+    let flac_tag = Tag::read_from_path(flac_path)?;
+
+    let info_blocks = flac_tag.get_blocks(BlockType::StreamInfo);
+
+    if let Some(Block::StreamInfo(stream_info_block)) = info_blocks.first() {
+        Ok(stream_info_block.total_samples * BYTES_PER_SAMPLE)
+    }
+    else {
+        bail!("no stream info block found");
+    }
+}
+
+/// Compares decoded samples against an expected MD5 signature.
+/// The samples must be interleaved, signed and little-endian, which is the form FLAC signs.
+pub fn check_md5(samples: &[u8], expected_md5: Option<&[u8]>) -> Md5Check {
+    match expected_md5 {
+        None => Md5Check::Unavailable,
+        Some(expected_md5) => {
+            if md5::compute(samples).0 == expected_md5 {
+                Md5Check::Match
+            }
+            else {
+                Md5Check::Mismatch
+            }
+        },
+    }
+}
+
+/// Decodes a FLAC file and checks the decoded audio against its MD5 signature.
+/// A mismatch means the file is corrupted, even if it still decodes without errors.
+/// `on_read` is called as in `decode_flac_file`.
+pub fn decode_and_verify_flac_file<P: AsRef<Path>>(
+    decoder: &Decoder,
+    flac_path: P,
+    on_read: &mut FnMut(u64),
+) -> Result<(Vec<u8>, Md5Check), failure::Error> {
+    let expected_md5 = get_stream_md5(flac_path.as_ref())?;
+    let samples = decode_flac_file(decoder, flac_path, on_read)?;
+
+    let md5_check = check_md5(&samples, expected_md5.as_ref().map(Vec::as_slice));
+    debug!("decoded {} bytes of audio, MD5 check: {:?}", samples.len(), md5_check);
+
+    Ok((samples, md5_check))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_md5;
+    use super::DecoderKind;
+    use super::Md5Check;
+
+    #[test]
+    fn test_check_md5() {
+        // MD5 of the empty string.
+        let empty_md5: &[u8] = &[
+            0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04,
+            0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8, 0x42, 0x7e,
+        ];
+
+        let inputs_and_expected = vec![
+            ((&b""[..], Some(empty_md5)), Md5Check::Match),
+            ((&b"\x01\x00"[..], Some(empty_md5)), Md5Check::Mismatch),
+            ((&b"\x01\x00"[..], None), Md5Check::Unavailable),
+        ];
+
+        for ((samples, expected_md5), expected) in inputs_and_expected {
+            let produced = check_md5(samples, expected_md5);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_decoder_kind_from_str() {
+        assert_eq!(DecoderKind::Flac, "flac".parse::<DecoderKind>().unwrap());
+        assert_eq!(DecoderKind::Ffmpeg, "ffmpeg".parse::<DecoderKind>().unwrap());
+        assert!("sox".parse::<DecoderKind>().is_err());
+    }
+}
@@ -1,4 +1,5 @@
 //! Finds, filters, and sorts file paths in a directory to get the working set of FLAC files to use.
+use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -12,20 +13,90 @@ pub fn get_flac_files_in_dir<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, fai
     let mut res: Vec<_> = glob(&pattern.to_string_lossy())?.filter_map(Result::ok).collect();
     res.sort();
 
+    debug!("found {} FLAC files in {}", res.len(), dir.display());
+    for (index, path) in res.iter().enumerate() {
+        debug!("  {:>2}: {}", index + 1, path.display());
+    }
+
     Ok(res)
 }
 
+/// Recursively finds every directory under `root`, including `root` itself, that directly contains FLAC files.
+/// Each such directory is assumed to hold a single disc.
+pub fn find_album_dirs<P: AsRef<Path>>(root: P) -> Result<Vec<PathBuf>, failure::Error> {
+    let mut album_dirs = vec![];
+    let mut pending = vec![root.as_ref().to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+
+            if path.is_dir() {
+                pending.push(path);
+            }
+        }
+
+        if !get_flac_files_in_dir(&dir)?.is_empty() {
+            album_dirs.push(dir);
+        }
+    }
+
+    album_dirs.sort();
+
+    Ok(album_dirs)
+}
+
+/// Returns the track number a file name starts with, e.g. 3 for `03 - Title.flac`.
+pub fn track_number_from_file_name<P: AsRef<Path>>(path: P) -> Option<u8> {
+    let stem = path.as_ref().file_stem()?.to_string_lossy().into_owned();
+    let digits: String = stem.chars().take_while(|c| c.is_ascii_digit()).collect();
+
+    digits.parse().ok()
+}
+
+/// Returns true if a file name starts with track number 0, e.g. `00.flac` or `00 - Hidden.flac`.
+/// This is how rippers name files containing hidden track one audio (HTOA) from track 1's pregap.
+fn is_htoa_file<P: AsRef<Path>>(path: P) -> bool {
+    track_number_from_file_name(path) == Some(0)
+}
+
+/// Pairs each file of an incomplete disc with the track number its name starts with.
+pub fn number_tracks_by_file_name(flac_files: Vec<PathBuf>) -> Result<Vec<(u8, PathBuf)>, failure::Error> {
+    flac_files.into_iter().map(|flac_file| match track_number_from_file_name(&flac_file) {
+        Some(track_num) if track_num > 0 => Ok((track_num, flac_file)),
+        _ => bail!("unable to tell the track number of {} from its name", flac_file.display()),
+    }).collect()
+}
+
+/// Separates a leading HTOA file, if any, from a sorted list of FLAC files.
+pub fn split_htoa_file(mut flac_files: Vec<PathBuf>) -> (Option<PathBuf>, Vec<PathBuf>) {
+    if flac_files.first().map_or(false, is_htoa_file) {
+        let htoa_file = flac_files.remove(0);
+        debug!("treating {} as hidden track one audio", htoa_file.display());
+        (Some(htoa_file), flac_files)
+    }
+    else {
+        (None, flac_files)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test_util;
     extern crate tempfile;
 
+    use std::fs;
     use std::fs::File;
+    use std::path::PathBuf;
     // use std::thread::sleep_ms;
 
     use self::tempfile::tempdir;
 
     use super::get_flac_files_in_dir;
+    use super::find_album_dirs;
+    use super::split_htoa_file;
+    use super::track_number_from_file_name;
+    use super::number_tracks_by_file_name;
 
     #[test]
     fn test_get_flac_files_in_dir() {
@@ -56,4 +127,95 @@ mod tests {
 
         assert_eq!(expected, produced);
     }
+
+    #[test]
+    fn test_find_album_dirs() {
+        let dir = tempdir().unwrap();
+
+        for album in &["Artist A/Album 1", "Artist A/Album 2/CD 1", "Artist A/Album 2/CD 2", "Artist B/Empty"] {
+            fs::create_dir_all(dir.path().join(album)).unwrap();
+        }
+
+        for album in &["Artist A/Album 1", "Artist A/Album 2/CD 1", "Artist A/Album 2/CD 2"] {
+            File::create(dir.path().join(album).join("01.flac")).unwrap();
+        }
+
+        File::create(dir.path().join("Artist B").join("Empty").join("cover.jpg")).unwrap();
+
+        let expected = vec![
+            dir.path().join("Artist A/Album 1"),
+            dir.path().join("Artist A/Album 2/CD 1"),
+            dir.path().join("Artist A/Album 2/CD 2"),
+        ];
+
+        let produced = find_album_dirs(dir.path()).unwrap();
+
+        assert_eq!(expected, produced);
+    }
+
+    #[test]
+    fn test_split_htoa_file() {
+        let inputs_and_expected = vec![
+            (
+                vec!["00.flac", "01.flac", "02.flac"],
+                (Some("00.flac"), vec!["01.flac", "02.flac"]),
+            ),
+            (
+                vec!["00 - Hidden.flac", "01 - First.flac"],
+                (Some("00 - Hidden.flac"), vec!["01 - First.flac"]),
+            ),
+            (
+                vec!["01.flac", "02.flac"],
+                (None, vec!["01.flac", "02.flac"]),
+            ),
+            (
+                vec!["100.flac"],
+                (None, vec!["100.flac"]),
+            ),
+            (
+                vec![],
+                (None, vec![]),
+            ),
+        ];
+
+        for (input, (expected_htoa, expected_tracks)) in inputs_and_expected {
+            let input: Vec<PathBuf> = input.into_iter().map(PathBuf::from).collect();
+            let expected = (
+                expected_htoa.map(PathBuf::from),
+                expected_tracks.into_iter().map(PathBuf::from).collect::<Vec<_>>(),
+            );
+
+            let produced = split_htoa_file(input);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_track_number_from_file_name() {
+        let inputs_and_expected = vec![
+            ("03 - Title.flac", Some(3)),
+            ("/music/album/12.flac", Some(12)),
+            ("00 - Hidden.flac", Some(0)),
+            ("007.flac", Some(7)),
+            ("Title.flac", None),
+            ("1000.flac", None),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = track_number_from_file_name(input);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_number_tracks_by_file_name() {
+        let input = vec![PathBuf::from("02 - Two.flac"), PathBuf::from("07 - Seven.flac")];
+        let expected = vec![(2, PathBuf::from("02 - Two.flac")), (7, PathBuf::from("07 - Seven.flac"))];
+
+        let produced = number_tracks_by_file_name(input).unwrap();
+        assert_eq!(expected, produced);
+
+        assert!(number_tracks_by_file_name(vec![PathBuf::from("Bonus.flac")]).is_err());
+        assert!(number_tracks_by_file_name(vec![PathBuf::from("00 - Hidden.flac")]).is_err());
+    }
 }
@@ -1,53 +1,298 @@
+use std::env;
 use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use reqwest;
+use reqwest::header::Headers;
+use reqwest::header::UserAgent;
 use failure;
 use byteorder::LittleEndian;
 use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
 
 use offset::DiscInfo;
+use cache::BinCache;
 
-const ACCURATERIP_DB_URL: &str = "http://www.accuraterip.com/accuraterip";
+/// Default base URL of the AccurateRip database.
+pub const ACCURATERIP_DB_URL: &str = "http://www.accuraterip.com/accuraterip";
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct TrackResult {
-    confidence: u8,
-    crc: u32,
+    pub confidence: u8,
+    pub crc: u32,
     // _unused: u32,
 }
 
-fn create_ar_bin_url(disc_info: &DiscInfo) -> String {
+/// Returns the path of the dBAR file for a disc relative to the root of the database, e.g. `f/a/a/dBAR-...bin`.
+pub fn ar_bin_path(disc_info: &DiscInfo) -> String {
     format!(
-        "{}/{:x}/{:x}/{:x}/dBAR-{:0>3}-{:0>8x}-{:0>8x}-{:0>8x}.bin",
-        ACCURATERIP_DB_URL,
+        "{:x}/{:x}/{:x}/dBAR-{}.bin",
         disc_info.id_1 & 0xF,
         disc_info.id_1 >> 4 & 0xF,
         disc_info.id_1 >> 8 & 0xF,
-        disc_info.num_tracks,
-        disc_info.id_1,
-        disc_info.id_2,
-        disc_info.cddb_id,
+        disc_info.ar_disc_id(),
     )
 }
 
-pub fn get_ar_bin(disc_info: &DiscInfo) -> Result<Vec<u8>, failure::Error> {
-    let url = create_ar_bin_url(disc_info);
+pub fn create_ar_bin_url(base_url: &str, disc_info: &DiscInfo) -> String {
+    format!("{}/{}", base_url.trim_right_matches('/'), ar_bin_path(disc_info))
+}
+
+/// Anything that dBAR files can be looked up in, such as the database itself or a local mirror of it.
+pub trait BinSource {
+    /// Returns the raw dBAR file for a disc, or `None` if the disc is not present.
+    fn get_ar_bin(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error>;
+}
+
+/// Status and body of an HTTP response.
+#[derive(Clone, PartialEq, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Performs HTTP GET requests.
+/// Errors mean that no response was received at all, e.g. due to a timeout or a refused connection.
+pub trait Transport {
+    fn get(&self, url: &str) -> Result<HttpResponse, failure::Error>;
+}
+
+/// Which proxies requests go through.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ProxySettings {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    /// Hosts that are connected to directly, including their subdomains. `*` matches every host.
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    /// Uses the same proxy for every request.
+    pub fn for_all(proxy_url: &str) -> Self {
+        ProxySettings {
+            http: Some(proxy_url.to_string()),
+            https: Some(proxy_url.to_string()),
+            no_proxy: vec![],
+        }
+    }
+
+    /// Reads the conventional `http_proxy`, `https_proxy`, `all_proxy` and `no_proxy` environment variables.
+    pub fn from_env() -> Self {
+        ProxySettings::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Lowercase variable names take precedence over uppercase ones, and empty variables count as unset.
+    fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Self {
+        let lookup = |name: &str| {
+            var(name).or_else(|| var(&name.to_uppercase())).filter(|value| !value.is_empty())
+        };
+
+        let all = lookup("all_proxy");
+
+        ProxySettings {
+            http: lookup("http_proxy").or_else(|| all.clone()),
+            https: lookup("https_proxy").or(all),
+            no_proxy: lookup("no_proxy").map(|hosts| parse_host_list(&hosts)).unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.http.is_none() && self.https.is_none()
+    }
+
+    /// Returns the proxy to use for a request, or `None` if the request should be made directly.
+    pub fn proxy_for(&self, scheme: &str, host: &str) -> Option<&str> {
+        let host = host.to_lowercase();
+
+        let bypassed = self.no_proxy.iter().any(|pattern| {
+            pattern == "*" || host == *pattern || host.ends_with(&format!(".{}", pattern))
+        });
 
-    let mut response = reqwest::get(&url)?;
+        if bypassed {
+            return None;
+        }
+
+        match scheme {
+            "http" => self.http.as_ref().map(String::as_str),
+            "https" => self.https.as_ref().map(String::as_str),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a comma-separated list of hosts, as used by `no_proxy`.
+pub fn parse_host_list(hosts: &str) -> Vec<String> {
+    hosts.split(',')
+        .map(|host| host.trim().trim_left_matches('.').to_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(timeout: Duration, proxy_settings: ProxySettings, user_agent: &str) -> Result<Self, failure::Error> {
+        let mut builder = reqwest::Client::builder();
+        builder.timeout(timeout);
+
+        if !proxy_settings.is_empty() {
+            builder.proxy(reqwest::Proxy::custom(move |url| {
+                proxy_settings.proxy_for(url.scheme(), url.host_str().unwrap_or("")).map(str::to_string)
+            }));
+        }
+
+        let mut headers = Headers::new();
+        headers.set(UserAgent::new(user_agent.to_string()));
+        builder.default_headers(headers);
+
+        Ok(ReqwestTransport { client: builder.build()? })
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn get(&self, url: &str) -> Result<HttpResponse, failure::Error> {
+        let mut response = self.client.get(url).send()?;
+
+        let mut body = vec![];
+        response.copy_to(&mut body)?;
+
+        Ok(HttpResponse { status: response.status().as_u16(), body })
+    }
+}
+
+/// How failed requests are retried, and how requests are spaced out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RetryPolicy {
+    /// Number of attempts made after the first one fails.
+    pub max_retries: u32,
+    /// Delay before the first retry, which doubles with every further retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Smallest interval between the starts of two requests, across all requests made by a fetcher.
+    pub min_interval: Duration,
+}
 
-    match response.status() {
-        reqwest::StatusCode::Ok => {
-            let mut buffer: Vec<u8> = vec![];
-            response.copy_to(&mut buffer)?;
+impl RetryPolicy {
+    /// Returns the delay before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::max_value());
 
-            Ok(buffer)
-        },
-        reqwest::StatusCode::NotFound => {
-            bail!("disc not present in database");
-        },
-        _ => {
-            bail!("error when fetching bin file");
-        },
+        self.initial_backoff.checked_mul(factor).map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Server errors and rate limiting are usually transient, other errors are not.
+fn is_retryable_status(status: u16) -> bool {
+    status >= 500 || status == 429
+}
+
+/// Fetches dBAR files from the AccurateRip database or a mirror of it, optionally through an on-disk cache.
+pub struct Fetcher<T: Transport> {
+    transport: T,
+    base_url: String,
+    cache: Option<BinCache>,
+    policy: RetryPolicy,
+    last_request: Mutex<Option<Instant>>,
+}
+
+impl<T: Transport> Fetcher<T> {
+    pub fn new(transport: T, base_url: &str, cache: Option<BinCache>, policy: RetryPolicy) -> Self {
+        Fetcher {
+            transport,
+            base_url: base_url.to_string(),
+            cache,
+            policy,
+            last_request: Mutex::new(None),
+        }
+    }
+
+    pub fn ar_bin_url(&self, disc_info: &DiscInfo) -> String {
+        create_ar_bin_url(&self.base_url, disc_info)
+    }
+
+    /// Blocks until the minimum interval since the previous request has passed.
+    fn wait_for_turn(&self) {
+        let mut last_request = self.last_request.lock().unwrap();
+
+        if let Some(last_request) = *last_request {
+            let elapsed = last_request.elapsed();
+
+            if elapsed < self.policy.min_interval {
+                thread::sleep(self.policy.min_interval - elapsed);
+            }
+        }
+
+        *last_request = Some(Instant::now());
+    }
+
+    /// Fetches a URL, retrying with backoff on connection errors, server errors and rate limiting.
+    /// Returns `None` if the server responds with 404.
+    pub fn get(&self, url: &str) -> Result<Option<Vec<u8>>, failure::Error> {
+        let mut retry = 0;
+
+        loop {
+            self.wait_for_turn();
+
+            debug!("GET {}", url);
+            let response = self.transport.get(url);
+
+            if let Ok(ref response) = response {
+                debug!("HTTP status {}, {} bytes", response.status, response.body.len());
+            }
+
+            let error = match response {
+                Ok(HttpResponse { status: 200, body }) => return Ok(Some(body)),
+                Ok(HttpResponse { status: 404, .. }) => return Ok(None),
+                Ok(HttpResponse { status, .. }) => {
+                    let error = format_err!("error when fetching {}: HTTP status {}", url, status);
+
+                    if !is_retryable_status(status) {
+                        return Err(error);
+                    }
+
+                    error
+                },
+                Err(err) => format_err!("error when fetching {}: {}", url, err),
+            };
+
+            if retry >= self.policy.max_retries {
+                bail!("{} (gave up after {} attempts)", error, retry + 1);
+            }
+
+            let backoff = self.policy.backoff(retry);
+            warn!("{}, retrying in {:?}", error, backoff);
+
+            thread::sleep(backoff);
+            retry += 1;
+        }
+    }
+}
+
+impl<T: Transport> BinSource for Fetcher<T> {
+    /// Discs missing from the database are not cached, since they may be submitted at any time.
+    /// Failing to cache a dBAR file is only logged, since the downloaded data is still good.
+    fn get_ar_bin(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+        if let Some(ref cache) = self.cache {
+            if let Some(ar_bin_data) = cache.get(disc_info)? {
+                debug!("using cached dBAR file for disc {}", disc_info.ar_disc_id());
+                return Ok(Some(ar_bin_data));
+            }
+        }
+
+        let ar_bin_data = self.get(&self.ar_bin_url(disc_info))?;
+
+        if let (Some(ref cache), Some(ar_bin_data)) = (self.cache.as_ref(), ar_bin_data.as_ref()) {
+            if let Err(err) = cache.put(disc_info, ar_bin_data) {
+                warn!("unable to cache dBAR file for disc {}: {}", disc_info.ar_disc_id(), err);
+            }
+        }
+
+        Ok(ar_bin_data)
     }
 }
 
@@ -89,69 +334,330 @@ pub fn unpack_ar_bin(ar_bin_data: &[u8]) -> Result<Vec<(DiscInfo, Vec<TrackResul
     Ok(results)
 }
 
+/// Packs CRC sets into the dBAR file format, the inverse of `unpack_ar_bin`.
+/// The unused field of each track result is written as zero.
+pub fn pack_ar_bin(results: &[(DiscInfo, Vec<TrackResult>)]) -> Result<Vec<u8>, failure::Error> {
+    let mut ar_bin_data = vec![];
+
+    for &(ref disc_info, ref track_results) in results {
+        if track_results.len() != disc_info.num_tracks as usize {
+            bail!("expected {} track results, got {}", disc_info.num_tracks, track_results.len());
+        }
+
+        ar_bin_data.write_u8(disc_info.num_tracks)?;
+        ar_bin_data.write_u32::<LittleEndian>(disc_info.id_1)?;
+        ar_bin_data.write_u32::<LittleEndian>(disc_info.id_2)?;
+        ar_bin_data.write_u32::<LittleEndian>(disc_info.cddb_id)?;
+
+        for track_result in track_results {
+            ar_bin_data.write_u8(track_result.confidence)?;
+            ar_bin_data.write_u32::<LittleEndian>(track_result.crc)?;
+            ar_bin_data.write_u32::<LittleEndian>(0)?;
+        }
+    }
+
+    Ok(ar_bin_data)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
     use std::path::Path;
-    use std::path::PathBuf;
     use std::io::Read;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    extern crate tempfile;
+
+    use failure;
 
+    use self::tempfile::tempdir;
+
+    use cache::BinCache;
     use offset::DiscInfo;
+    use offset::SAMPLE_DISC_INFO;
 
     use super::create_ar_bin_url;
-    use super::get_ar_bin;
+    use super::ACCURATERIP_DB_URL;
     use super::unpack_ar_bin;
+    use super::pack_ar_bin;
+    use super::BinSource;
+    use super::Fetcher;
+    use super::HttpResponse;
+    use super::ProxySettings;
+    use super::ReqwestTransport;
+    use super::RetryPolicy;
+    use super::Transport;
     use super::TrackResult;
 
-    use test_util::load_bytes;
+    use test_util::load_sample_bin;
 
     #[test]
     fn test_create_ar_bin_url() {
+        let disc_info = SAMPLE_DISC_INFO;
+
         let inputs_and_expected = vec![
             (
-                DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 10 },
+                ACCURATERIP_DB_URL,
                 "http://www.accuraterip.com/accuraterip/f/a/a/dBAR-010-0012baaf-0094edfd-8b0b500a.bin",
             ),
+            (
+                "http://mirror.example.com/ar/",
+                "http://mirror.example.com/ar/f/a/a/dBAR-010-0012baaf-0094edfd-8b0b500a.bin",
+            ),
         ];
 
         for (input, expected) in inputs_and_expected {
-            let produced = create_ar_bin_url(&input);
+            let produced = create_ar_bin_url(input, &disc_info);
             assert_eq!(expected, produced);
         }
     }
 
+    fn no_delay_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+            min_interval: Duration::from_millis(0),
+        }
+    }
+
+    /// Replays canned responses, where `None` stands for a connection error.
+    struct MockTransport {
+        responses: Mutex<Vec<Option<u16>>>,
+        urls: Mutex<Vec<String>>,
+    }
+
+    impl MockTransport {
+        fn new(responses: Vec<Option<u16>>) -> Self {
+            MockTransport { responses: Mutex::new(responses), urls: Mutex::new(vec![]) }
+        }
+
+        fn num_requests(&self) -> usize {
+            self.urls.lock().unwrap().len()
+        }
+    }
+
+    impl<'a> Transport for &'a MockTransport {
+        fn get(&self, url: &str) -> Result<HttpResponse, failure::Error> {
+            self.urls.lock().unwrap().push(url.to_string());
+
+            match self.responses.lock().unwrap().remove(0) {
+                Some(status) => Ok(HttpResponse { status, body: vec![status as u8] }),
+                None => bail!("connection refused"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetcher_get() {
+        let inputs_and_expected = vec![
+            (vec![Some(200)], (Some(Some(vec![200])), 1)),
+            (vec![Some(404)], (Some(None), 1)),
+            // Transient failures are retried.
+            (vec![Some(503), None, Some(200)], (Some(Some(vec![200])), 3)),
+            (vec![Some(429), Some(404)], (Some(None), 2)),
+            // Client errors are not retried.
+            (vec![Some(403), Some(200)], (None, 1)),
+            // Retries run out.
+            (vec![Some(500), None, Some(502), Some(200)], (None, 3)),
+        ];
+
+        for (input, (expected_result, expected_requests)) in inputs_and_expected {
+            let transport = MockTransport::new(input);
+            let fetcher = Fetcher::new(&transport, "http://example.com", None, no_delay_policy());
+
+            let produced = fetcher.get("http://example.com/a.bin");
+
+            assert_eq!(expected_result, produced.as_ref().ok().cloned());
+            assert_eq!(expected_requests, transport.num_requests());
+        }
+    }
+
+    #[test]
+    fn test_fetcher_get_error_message() {
+        let transport = MockTransport::new(vec![Some(403)]);
+        let fetcher = Fetcher::new(&transport, "http://example.com", None, no_delay_policy());
+
+        let message = fetcher.get("http://example.com/a.bin").unwrap_err().to_string();
+        assert!(message.contains("http://example.com/a.bin"));
+        assert!(message.contains("403"));
+
+        let transport = MockTransport::new(vec![None, None, None]);
+        let fetcher = Fetcher::new(&transport, "http://example.com", None, no_delay_policy());
+
+        let message = fetcher.get("http://example.com/a.bin").unwrap_err().to_string();
+        assert!(message.contains("connection refused"));
+        assert!(message.contains("3 attempts"));
+    }
+
+    #[test]
+    fn test_fetcher_min_interval() {
+        let transport = MockTransport::new(vec![Some(200), Some(200), Some(200)]);
+        let policy = RetryPolicy { min_interval: Duration::from_millis(30), ..no_delay_policy() };
+        let fetcher = Fetcher::new(&transport, "http://example.com", None, policy);
+
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            fetcher.get("http://example.com/a.bin").unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+            min_interval: Duration::from_millis(0),
+        };
+
+        let inputs_and_expected = vec![
+            (0, Duration::from_millis(500)),
+            (1, Duration::from_millis(1000)),
+            (2, Duration::from_millis(2000)),
+            (3, Duration::from_secs(3)),
+            (40, Duration::from_secs(3)),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            assert_eq!(expected, policy.backoff(input));
+        }
+    }
+
+    #[test]
+    fn test_proxy_settings_from_vars() {
+        let vars = vec![
+            ("HTTP_PROXY", "http://upper:3128"),
+            ("http_proxy", "http://lower:3128"),
+            ("all_proxy", "http://all:1080"),
+            ("NO_PROXY", "localhost, .internal.example.com,"),
+        ];
+        let var = |name: &str| vars.iter().find(|&&(n, _)| n == name).map(|&(_, v)| v.to_string());
+
+        let expected = ProxySettings {
+            http: Some("http://lower:3128".to_string()),
+            https: Some("http://all:1080".to_string()),
+            no_proxy: vec!["localhost".to_string(), "internal.example.com".to_string()],
+        };
+
+        assert_eq!(expected, ProxySettings::from_vars(var));
+        assert!(ProxySettings::from_vars(|_| Some(String::new())).is_empty());
+    }
+
+    #[test]
+    fn test_proxy_settings_proxy_for() {
+        let settings = ProxySettings {
+            http: Some("http://proxy:3128".to_string()),
+            https: None,
+            no_proxy: vec!["internal.example.com".to_string()],
+        };
+
+        let inputs_and_expected = vec![
+            (("http", "www.accuraterip.com"), Some("http://proxy:3128")),
+            (("https", "www.accuraterip.com"), None),
+            (("http", "internal.example.com"), None),
+            (("http", "mirror.INTERNAL.example.com"), None),
+            (("http", "notinternal.example.com"), Some("http://proxy:3128")),
+        ];
+
+        for ((scheme, host), expected) in inputs_and_expected {
+            assert_eq!(expected, settings.proxy_for(scheme, host));
+        }
+
+        let bypass_all = ProxySettings { no_proxy: vec!["*".to_string()], ..ProxySettings::for_all("http://proxy:3128") };
+        assert_eq!(None, bypass_all.proxy_for("http", "www.accuraterip.com"));
+    }
+
+    #[test]
+    fn test_reqwest_transport_proxy() {
+        // Stands in for a proxy: records the request it receives, and answers it without forwarding it.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let proxy = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_lines = vec![];
+
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+
+                if line.trim().is_empty() {
+                    break;
+                }
+
+                request_lines.push(line.trim().to_string());
+            }
+
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nbin").unwrap();
+
+            request_lines
+        });
+
+        let url = "http://www.accuraterip.com/accuraterip/f/a/a/dBAR-010-0012baaf-0094edfd-8b0b500a.bin";
+        let transport = ReqwestTransport::new(Duration::from_secs(10), ProxySettings::for_all(&proxy_url), "test-agent/1.0").unwrap();
+
+        let produced = transport.get(url).unwrap();
+        assert_eq!(HttpResponse { status: 200, body: b"bin".to_vec() }, produced);
+
+        // Requests to a proxy use the absolute URL as the request target.
+        let request_lines = proxy.join().unwrap();
+        assert_eq!(format!("GET {} HTTP/1.1", url), request_lines[0]);
+        assert!(request_lines.iter().any(|line| line.to_lowercase() == "user-agent: test-agent/1.0"));
+    }
+
+    #[test]
+    fn test_get_ar_bin_unwritable_cache() {
+        let dir = tempdir().unwrap();
+        let blocking_file = dir.path().join("file");
+        File::create(&blocking_file).unwrap();
+
+        // The cache directory cannot be created below a file.
+        let cache = BinCache::new(blocking_file.join("cache"), Duration::from_secs(3600));
+        let transport = MockTransport::new(vec![Some(200)]);
+        let fetcher = Fetcher::new(&transport, "http://example.com", Some(cache), no_delay_policy());
+
+        let disc_info = SAMPLE_DISC_INFO;
+        assert_eq!(Some(vec![200]), fetcher.get_ar_bin(&disc_info).unwrap());
+    }
+
     #[test]
     #[ignore("pulls data from AccurateRip server")]
     fn test_get_ar_bin() {
-        let bin_dir = PathBuf::from("test_util").join("input").join("bin");
-
         let inputs_and_expected = vec![
             (
-                DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 10 },
-                load_bytes(bin_dir.join("dBAR-010-0012baaf-0094edfd-8b0b500a.bin")),
+                SAMPLE_DISC_INFO,
+                Some(load_sample_bin()),
             ),
         ];
 
+        let transport = ReqwestTransport::new(Duration::from_secs(30), ProxySettings::from_env(), "ar-flac-rs-test").unwrap();
+        let fetcher = Fetcher::new(transport, ACCURATERIP_DB_URL, None, no_delay_policy());
+
         for (input, expected) in inputs_and_expected {
-            let produced = get_ar_bin(&input).unwrap();
+            let produced = fetcher.get_ar_bin(&input).unwrap();
             assert_eq!(expected, produced);
         }
     }
 
     #[test]
     fn test_unpack_ar_bin() {
-        let bin_dir = PathBuf::from("test_util").join("input").join("bin");
-
-        let disc_info = DiscInfo {
-            id_1: 1227439,
-            id_2: 9760253,
-            cddb_id: 2332774410,
-            num_tracks: 10,
-        };
+        let disc_info = SAMPLE_DISC_INFO;
 
         let inputs_and_expected = vec![
             (
-                load_bytes(bin_dir.join("dBAR-010-0012baaf-0094edfd-8b0b500a.bin")),
+                load_sample_bin(),
                 vec![
                     (disc_info, vec![TrackResult { confidence: 122, crc: 4158045718 }, TrackResult { confidence: 123, crc: 3175593300 }, TrackResult { confidence: 125, crc: 1895033188 }, TrackResult { confidence: 123, crc: 1209064292 }, TrackResult { confidence: 123, crc: 751048154 }, TrackResult { confidence: 122,crc: 2692720149 }, TrackResult { confidence: 122, crc: 3342672821 }, TrackResult { confidence: 119, crc: 41310113 }, TrackResult { confidence: 121, crc: 3288026773 }, TrackResult {confidence: 122, crc: 2772935668 }]),
                     (disc_info, vec![TrackResult { confidence: 119, crc: 3733010837 }, TrackResult { confidence: 120, crc: 3824549311 }, TrackResult { confidence: 119, crc: 1038071824 }, TrackResult { confidence: 120, crc: 1723505091 }, TrackResult { confidence: 122, crc: 841709511 }, TrackResult { confidence: 121,crc: 3087785059 }, TrackResult { confidence: 121, crc: 2819070029 }, TrackResult { confidence: 119, crc: 4152591618 }, TrackResult { confidence: 119, crc: 2834344192 }, TrackResult{ confidence: 120, crc: 1799856152 }]),
@@ -171,4 +677,26 @@ mod tests {
             assert_eq!(expected, produced);
         }
     }
+
+    #[test]
+    fn test_pack_ar_bin() {
+        let disc_info = DiscInfo { id_1: 0x00000123, id_2: 0x00000456, cddb_id: 0x01000202, num_tracks: 2 };
+
+        let results = vec![
+            (disc_info, vec![TrackResult { confidence: 3, crc: 0x11223344 }, TrackResult { confidence: 255, crc: 0 }]),
+            (disc_info, vec![TrackResult { confidence: 1, crc: 5 }, TrackResult { confidence: 1, crc: 6 }]),
+        ];
+
+        let produced = pack_ar_bin(&results).unwrap();
+
+        assert_eq!(2 * (13 + 2 * 9), produced.len());
+        assert_eq!(
+            vec![2, 0x23, 0x01, 0, 0, 0x56, 0x04, 0, 0, 0x02, 0x02, 0, 0x01, 3, 0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0],
+            produced[..22].to_vec(),
+        );
+        assert_eq!(results, unpack_ar_bin(&produced).unwrap());
+
+        // The number of track results has to match the disc.
+        assert!(pack_ar_bin(&[(disc_info, vec![TrackResult { confidence: 1, crc: 5 }])]).is_err());
+    }
 }
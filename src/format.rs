@@ -0,0 +1,181 @@
+//! Renders validation reports as human-readable text or as versioned JSON.
+
+use std::str::FromStr;
+
+use failure;
+use serde_json;
+
+use validate::ValidationReport;
+use validate::DiagnosticLevel;
+use validate::AccuracyRequirement;
+
+/// Version of the JSON output schema.
+/// Bumped whenever a field is renamed, removed, or changes meaning; new fields may be added without a bump.
+pub const JSON_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format: {}", s),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEnvelope<'a> {
+    schema_version: u32,
+    tool_version: &'static str,
+    report: &'a ValidationReport,
+}
+
+pub fn render(report: &ValidationReport, format: OutputFormat) -> Result<String, failure::Error> {
+    match format {
+        OutputFormat::Text => Ok(render_text(report)),
+        OutputFormat::Json => render_json(report),
+    }
+}
+
+pub fn render_json(report: &ValidationReport) -> Result<String, failure::Error> {
+    let envelope = JsonEnvelope {
+        schema_version: JSON_SCHEMA_VERSION,
+        tool_version: env!("CARGO_PKG_VERSION"),
+        report,
+    };
+
+    Ok(serde_json::to_string_pretty(&envelope)?)
+}
+
+pub fn render_text(report: &ValidationReport) -> String {
+    let mut lines = vec![];
+
+    lines.push(format!("Disc ID: {}", report.disc_info.ar_disc_id()));
+    lines.push(format!("Pressings in database: {}", report.num_pressings));
+
+    if let Some(matched_pressing) = report.matched_pressing {
+        lines.push(format!("Matched pressing: {}", matched_pressing));
+    }
+    else if report.mixed_pressings {
+        lines.push("Matched pressing: mixed".to_string());
+    }
+
+    lines.push(String::new());
+
+    for track in &report.tracks {
+        let pressing = match track.matched_pressing {
+            Some(pressing_index) => format!(", pressing {}", pressing_index),
+            None => String::new(),
+        };
+
+        lines.push(format!(
+            "Track {:>2}  [v1 {:0>8X}] [v2 {:0>8X}]  {} (confidence {}{})",
+            track.track_num, track.crcs.v1, track.crcs.v2, track.status.description(), track.total_confidence, pressing,
+        ));
+
+        let copy_checksums = &track.copy_checksums;
+        let mut details = vec![];
+
+        if let Some(peak_percent) = copy_checksums.peak_percent() {
+            details.push(format!("Peak level {:.1} %", peak_percent));
+        }
+        if let Some(crc32) = copy_checksums.crc32 {
+            details.push(format!("Copy CRC {:0>8X}", crc32));
+        }
+        if let Some(crc32_skip_zero) = copy_checksums.crc32_skip_zero {
+            details.push(format!("Copy CRC w/o null samples {:0>8X}", crc32_skip_zero));
+        }
+
+        if !details.is_empty() {
+            lines.push(format!("          {}", details.join("  ")));
+        }
+    }
+
+    if !report.diagnostics.is_empty() {
+        lines.push(String::new());
+
+        for diagnostic in &report.diagnostics {
+            let level = match diagnostic.level {
+                DiagnosticLevel::Info => "info",
+                DiagnosticLevel::Warning => "warning",
+            };
+
+            lines.push(format!("{}: {}", level, diagnostic.message));
+        }
+    }
+
+    lines.push(String::new());
+    lines.push(if report.meets(AccuracyRequirement::HighConfidenceV2) {
+        "All tracks accurately ripped".to_string()
+    }
+    else if report.is_accurate() {
+        "All tracks accurately ripped, but some only with low confidence or via v1 CRCs".to_string()
+    }
+    else {
+        "Not all tracks could be verified as accurate".to_string()
+    });
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json;
+    use serde_json::Value;
+
+    use offset::DiscInfo;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::CopyChecksums;
+    use config::ThresholdConfig;
+    use validate::build_report;
+    use validate::LocalTrack;
+
+    use super::render_json;
+    use super::OutputFormat;
+    use super::JSON_SCHEMA_VERSION;
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!(OutputFormat::Text, "text".parse::<OutputFormat>().unwrap());
+        assert_eq!(OutputFormat::Json, "json".parse::<OutputFormat>().unwrap());
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn test_render_json() {
+        let disc_info = DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 1 };
+        let local_track = LocalTrack {
+            path: PathBuf::from("01.flac"),
+            crcs: CRCs { v1: 1, v2: 2 },
+            copy_checksums: CopyChecksums { crc32: Some(3), crc32_skip_zero: None, peak: Some(4) },
+            md5_check: Md5Check::Match,
+        };
+        let report = build_report(disc_info, vec![local_track], None, &ThresholdConfig::default());
+
+        let produced: Value = serde_json::from_str(&render_json(&report).unwrap()).unwrap();
+
+        assert_eq!(JSON_SCHEMA_VERSION as u64, produced["schema_version"].as_u64().unwrap());
+        assert_eq!(1227439, produced["report"]["disc_info"]["id_1"].as_u64().unwrap());
+        assert_eq!("unknown", produced["report"]["tracks"][0]["status"].as_str().unwrap());
+        assert_eq!(2, produced["report"]["tracks"][0]["crcs"]["v2"].as_u64().unwrap());
+        assert_eq!("match", produced["report"]["tracks"][0]["md5_check"].as_str().unwrap());
+        assert_eq!(3, produced["report"]["tracks"][0]["copy_checksums"]["crc32"].as_u64().unwrap());
+        assert!(produced["report"]["tracks"][0]["copy_checksums"]["crc32_skip_zero"].is_null());
+        assert!(produced["report"]["tracks"][0]["matched_pressing"].is_null());
+        assert_eq!(0, produced["report"]["tracks"][0]["total_confidence"].as_u64().unwrap());
+        assert_eq!(false, produced["report"]["mixed_pressings"].as_bool().unwrap());
+        assert_eq!("warning", produced["report"]["diagnostics"][0]["level"].as_str().unwrap());
+    }
+}
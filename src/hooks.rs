@@ -0,0 +1,181 @@
+//! Runs user-configured commands after an album is verified, e.g. to tag, transcode or send notifications.
+//! Each command is run by the shell, gets the results in environment variables, and the JSON report on stdin.
+
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+
+use failure;
+
+use config::HookConfig;
+use format::render_json;
+use validate::ValidationReport;
+use validate::Verdict;
+
+pub const ALBUM_DIR_VAR: &str = "AR_FLAC_ALBUM_DIR";
+pub const VERDICT_VAR: &str = "AR_FLAC_VERDICT";
+pub const DISC_ID_VAR: &str = "AR_FLAC_DISC_ID";
+pub const NUM_TRACKS_VAR: &str = "AR_FLAC_NUM_TRACKS";
+pub const NUM_ACCURATE_VAR: &str = "AR_FLAC_NUM_ACCURATE";
+
+/// Returns the environment variables passed to hooks for a verified album.
+pub fn hook_env(album_dir: &Path, verdict: Verdict, report: &ValidationReport) -> Vec<(&'static str, String)> {
+    let num_accurate = report.tracks.iter().filter(|t| t.status.is_accurate()).count();
+
+    vec![
+        (ALBUM_DIR_VAR, album_dir.to_string_lossy().into_owned()),
+        (VERDICT_VAR, verdict.name().to_string()),
+        (DISC_ID_VAR, report.disc_info.ar_disc_id()),
+        (NUM_TRACKS_VAR, report.tracks.len().to_string()),
+        (NUM_ACCURATE_VAR, num_accurate.to_string()),
+    ]
+}
+
+/// Runs a single hook command, failing if it cannot be started or exits unsuccessfully.
+/// Output of the hook goes to stderr, to keep the output of this program parseable.
+fn run_hook(command: &str, env: &[(&'static str, String)], json_report: &str) -> Result<(), failure::Error> {
+    debug!("running hook: {}", command);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format_err!("unable to run hook `{}`: {}", command, err))?;
+
+    // Written from another thread, so that a hook writing a lot of output before reading its input cannot block.
+    let mut stdin = child.stdin.take().unwrap();
+    let json_report = json_report.to_string();
+    let writer = thread::spawn(move || match stdin.write_all(json_report.as_bytes()) {
+        // Hooks are free to ignore the report.
+        Err(ref err) if err.kind() == ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    });
+
+    let output = child.wait_with_output()?;
+    let write_result = writer.join().map_err(|_| format_err!("unable to pass report to hook `{}`", command))?;
+
+    eprint!("{}", String::from_utf8_lossy(&output.stdout));
+
+    if !output.status.success() {
+        match output.status.code() {
+            Some(code) => bail!("hook `{}` failed with exit status {}", command, code),
+            None => bail!("hook `{}` was terminated by a signal", command),
+        }
+    }
+
+    write_result.map_err(|err| format_err!("unable to pass report to hook `{}`: {}", command, err))
+}
+
+/// Runs every configured hook for a verified album, in order.
+/// In strict mode, the first failing hook stops the remaining ones and fails the album.
+/// Otherwise failures are only logged, so that e.g. an unreachable notification service does not fail verification.
+pub fn run_hooks<P: AsRef<Path>>(
+    hook_config: &HookConfig,
+    album_dir: P,
+    verdict: Verdict,
+    report: &ValidationReport,
+) -> Result<(), failure::Error> {
+    if hook_config.post_verify.is_empty() {
+        return Ok(());
+    }
+
+    let env = hook_env(album_dir.as_ref(), verdict, report);
+    let json_report = render_json(report)?;
+
+    for command in &hook_config.post_verify {
+        match run_hook(command, &env, &json_report) {
+            Ok(()) => {},
+            Err(err) if hook_config.strict => return Err(err),
+            Err(err) => warn!("{}", err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use self::tempfile::tempdir;
+
+    use offset::DiscInfo;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::CopyChecksums;
+    use config::HookConfig;
+    use config::ThresholdConfig;
+    use validate::build_report;
+    use validate::LocalTrack;
+    use validate::ValidationReport;
+    use validate::Verdict;
+
+    use super::run_hooks;
+
+    fn sample_report() -> ValidationReport {
+        let disc_info = DiscInfo { id_1: 1, id_2: 2, cddb_id: 3, num_tracks: 2 };
+        let local_tracks = (1..3).map(|i| LocalTrack {
+            path: PathBuf::from("/music/album").join(format!("{:02}.flac", i)),
+            crcs: CRCs { v1: i, v2: i },
+            copy_checksums: CopyChecksums::default(),
+            md5_check: Md5Check::Match,
+        }).collect();
+
+        build_report(disc_info, local_tracks, None, &ThresholdConfig::default())
+    }
+
+    fn hook_config(post_verify: Vec<String>, strict: bool) -> HookConfig {
+        HookConfig { post_verify, strict }
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let dir = tempdir().unwrap();
+        let env_path = dir.path().join("env");
+        let stdin_path = dir.path().join("stdin");
+        let report = sample_report();
+
+        let commands = vec![
+            format!("echo \"$AR_FLAC_VERDICT $AR_FLAC_ALBUM_DIR $AR_FLAC_DISC_ID $AR_FLAC_NUM_ACCURATE/$AR_FLAC_NUM_TRACKS\" > '{}'", env_path.display()),
+            format!("cat > '{}'", stdin_path.display()),
+            // Hooks that ignore the report are fine.
+            "true".to_string(),
+        ];
+
+        run_hooks(&hook_config(commands, true), "/music/album", Verdict::Unknown, &report).unwrap();
+
+        let produced = fs::read_to_string(&env_path).unwrap();
+        assert_eq!("unknown /music/album 002-00000001-00000002-00000003 0/2\n", produced);
+
+        let produced = fs::read_to_string(&stdin_path).unwrap();
+        assert_eq!(super::render_json(&report).unwrap(), produced);
+    }
+
+    #[test]
+    fn test_run_hooks_failure() {
+        let dir = tempdir().unwrap();
+        let marker_path = dir.path().join("marker");
+        let report = sample_report();
+
+        let commands = vec!["exit 3".to_string(), format!("touch '{}'", marker_path.display())];
+
+        // Without strict mode, failures are only logged and the remaining hooks still run.
+        run_hooks(&hook_config(commands.clone(), false), "/music/album", Verdict::Accurate, &report).unwrap();
+        assert!(marker_path.exists());
+
+        fs::remove_file(&marker_path).unwrap();
+
+        let err = run_hooks(&hook_config(commands, true), "/music/album", Verdict::Accurate, &report).unwrap_err();
+        assert_eq!("hook `exit 3` failed with exit status 3", err.to_string());
+        assert!(!marker_path.exists());
+    }
+}
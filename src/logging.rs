@@ -0,0 +1,77 @@
+//! Writes log messages to stderr, filtered by the verbosity chosen on the command line.
+
+use failure;
+use log;
+use log::LevelFilter;
+use log::Log;
+use log::Metadata;
+use log::Record;
+
+/// Prefix of the log targets of this crate, which are shown at every level up to the chosen one.
+/// Messages from dependencies are only shown at trace level, since they are rarely useful and very noisy.
+const CRATE_TARGET: &str = "ar_flac_rs";
+
+struct StderrLogger {
+    level: LevelFilter,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && (metadata.target().starts_with(CRATE_TARGET) || self.level == LevelFilter::Trace)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{:<5} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the log level for a number of `-v` and `-q` flags, where the default shows warnings and errors.
+pub fn level_for_verbosity(verbose: u64, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Installs the logger, which can only be done once.
+pub fn init(level: LevelFilter) -> Result<(), failure::Error> {
+    log::set_boxed_logger(Box::new(StderrLogger { level }))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+
+    use super::level_for_verbosity;
+
+    #[test]
+    fn test_level_for_verbosity() {
+        let inputs_and_expected = vec![
+            ((0, false), LevelFilter::Warn),
+            ((1, false), LevelFilter::Info),
+            ((2, false), LevelFilter::Debug),
+            ((3, false), LevelFilter::Trace),
+            ((7, false), LevelFilter::Trace),
+            ((0, true), LevelFilter::Error),
+            ((2, true), LevelFilter::Error),
+        ];
+
+        for ((verbose, quiet), expected) in inputs_and_expected {
+            let produced = level_for_verbosity(verbose, quiet);
+            assert_eq!(expected, produced);
+        }
+    }
+}
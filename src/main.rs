@@ -1,4 +1,5 @@
 #![feature(attr_literals)]
+#![cfg_attr(test, feature(test))]
 
 extern crate clap;
 extern crate glob;
@@ -7,6 +8,14 @@ extern crate metaflac;
 extern crate reqwest;
 extern crate byteorder;
 extern crate rayon;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate md5;
+extern crate toml;
+extern crate atty;
+#[macro_use] extern crate log;
+extern crate notify;
 
 #[cfg(test)] extern crate test_util;
 
@@ -17,18 +26,1071 @@ mod fetch;
 mod crc;
 mod validate;
 mod decode;
+mod format;
+mod tag;
+mod riplog;
+mod toc;
+mod checksum;
+mod correct;
+mod cache;
+mod config;
+mod mirror;
+mod submit;
+mod server;
+mod prefetch;
+mod progress;
+mod logging;
+mod report;
+mod hooks;
+mod reverify;
+mod watch;
 
-use clap::{Arg, App};
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use clap::{Arg, ArgGroup, App, AppSettings, ArgMatches, SubCommand};
+
+use checksum::ChecksumSelection;
+use config::Config;
+use fetch::BinSource;
+use mirror::Mirror;
+use mirror::SyncOutcome;
+use prefetch::PrefetchedBins;
+use progress::ProgressListener;
+use submit::MergeOutcome;
+
+/// Loads the configuration file, and overrides its settings with any given command line flags.
+fn load_config(matches: &ArgMatches) -> Result<Config, failure::Error> {
+    let mut config = Config::load(matches.value_of("config").map(Path::new))?;
+
+    if let Some(accuraterip_url) = matches.value_of("accuraterip_url") {
+        config.database.accuraterip_url = accuraterip_url.to_string();
+    }
+    if let Some(timeout) = matches.value_of("timeout") {
+        config.network.timeout_secs = timeout.parse()
+            .map_err(|_| format_err!("invalid timeout: {}", timeout))?;
+    }
+    if let Some(retries) = matches.value_of("retries") {
+        config.network.max_retries = retries.parse()
+            .map_err(|_| format_err!("invalid number of retries: {}", retries))?;
+    }
+    if let Some(proxy) = matches.value_of("proxy") {
+        config.network.proxy = Some(proxy.to_string());
+    }
+    if let Some(user_agent) = matches.value_of("user_agent") {
+        config.network.user_agent = user_agent.to_string();
+    }
+    if let Some(cache_dir) = matches.value_of("cache_dir") {
+        config.cache.dir = Some(PathBuf::from(cache_dir));
+    }
+    if matches.is_present("no_cache") {
+        config.cache.ttl_secs = 0;
+    }
+    if let Some(mirror_dir) = matches.value_of("mirror_dir") {
+        config.mirror.dir = Some(PathBuf::from(mirror_dir));
+    }
+    if let Some(decoder) = matches.value_of("decoder") {
+        config.decoder.kind = decoder.parse()?;
+    }
+    if let Some(flac_path) = matches.value_of("flac_path") {
+        config.decoder.flac_path = PathBuf::from(flac_path);
+    }
+    if let Some(min_confidence) = matches.value_of("min_confidence") {
+        config.thresholds.min_confidence = min_confidence.parse()
+            .map_err(|_| format_err!("invalid minimum confidence: {}", min_confidence))?;
+    }
+    if let Some(high_confidence) = matches.value_of("high_confidence") {
+        config.thresholds.high_confidence = high_confidence.parse()
+            .map_err(|_| format_err!("invalid high confidence: {}", high_confidence))?;
+    }
+    if let Some(require) = matches.value_of("require") {
+        config.thresholds.require = Some(require.parse()?);
+    }
+    if let Some(max_offset) = matches.value_of("max_offset") {
+        config.offset.max_offset = max_offset.parse()
+            .map_err(|_| format_err!("invalid maximum offset: {}", max_offset))?;
+    }
+    if let Some(max_in_flight) = matches.value_of("max_in_flight") {
+        config.network.max_in_flight_requests = max_in_flight.parse()
+            .map_err(|_| format_err!("invalid number of requests in flight: {}", max_in_flight))?;
+    }
+    if let Some(settle) = matches.value_of("settle") {
+        config.watch.settle_secs = settle.parse()
+            .map_err(|_| format_err!("invalid settle time: {}", settle))?;
+    }
+    if let Some(poll_interval) = matches.value_of("poll_interval") {
+        config.watch.poll_interval_secs = poll_interval.parse()
+            .map_err(|_| format_err!("invalid poll interval: {}", poll_interval))?;
+    }
+    if matches.is_present("poll") {
+        config.watch.notifications = false;
+    }
+    if let Some(move_to) = matches.value_of("move_to") {
+        config.watch.move_to = Some(PathBuf::from(move_to));
+    }
+    if let Some(hooks) = matches.values_of("hook") {
+        config.hooks.post_verify.extend(hooks.map(String::from));
+    }
+    if matches.is_present("strict_hooks") {
+        config.hooks.strict = true;
+    }
+    if let Some(format) = matches.value_of("format") {
+        config.output.format = format.parse()?;
+    }
+
+    Ok(config)
+}
+
+/// Returns the number of `-v` flags and whether `-q` was given.
+/// Both are global flags that can be given at any level of subcommands, so every level is checked.
+fn verbosity(matches: &ArgMatches) -> (u64, bool) {
+    let mut verbose = matches.occurrences_of("verbose");
+    let mut quiet = matches.is_present("quiet");
+
+    if let (_, Some(sub_matches)) = matches.subcommand() {
+        let (sub_verbose, sub_quiet) = verbosity(sub_matches);
+        verbose = verbose.max(sub_verbose);
+        quiet |= sub_quiet;
+    }
+
+    (verbose, quiet)
+}
+
+/// Returns a listener that displays progress on the terminal, unless disabled.
+fn progress_listener(matches: &ArgMatches) -> Box<ProgressListener> {
+    if matches.is_present("no_progress") || matches.is_present("quiet") {
+        Box::new(progress::NoProgress)
+    }
+    else {
+        progress::for_terminal()
+    }
+}
+
+fn run_validate(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let flac_dir = matches.value_of("FLAC_DIR").unwrap();
+    let output_format = config.output.format;
+    let should_tag = matches.is_present("tag");
+    let dry_run = matches.is_present("dry_run");
+
+    let selection: ChecksumSelection = matches.value_of("checksums").unwrap().parse()?;
+
+    // With a separately given TOC, the files only need to be some of the tracks of the disc.
+    let toc = toc_from_args(matches)?;
+
+    let fetcher = config.fetcher()?;
+    let progress = progress_listener(matches);
+    let report = match toc {
+        Some(ref toc) => partial_tracks(flac_dir, matches.value_of("tracks"))
+            .and_then(|tracks| validate::validate_partial(&tracks, toc, selection, config, &fetcher, &*progress)),
+        None => validate::validate(flac_dir, selection, config, &fetcher, &*progress),
+    };
+    progress.finish();
+    let report = report?;
+
+    if should_tag {
+        let changes = tag::write_tags(&report, dry_run)?;
+
+        // Written to stderr to keep stdout parseable when using JSON output.
+        for change in changes {
+            eprintln!(
+                "{} {}: {}={} (was {})",
+                if dry_run { "would set" } else { "set" },
+                change.path.display(),
+                change.key,
+                change.new_value,
+                change.old_value.as_ref().map(String::as_str).unwrap_or("unset"),
+            );
+        }
+    }
+
+    if let Some(report_path) = matches.value_of("report") {
+        let toc = match toc {
+            Some(toc) => toc,
+            None => validate::identify_album(flac_dir)?.toc,
+        };
+
+        report::save_report(report_path, &toc, &report, config.report.key())?;
+    }
+
+    println!("{}", format::render(&report, output_format)?);
+
+    let verdict = validate::Verdict::for_report(&report, config.thresholds.require.unwrap_or(validate::AccuracyRequirement::Any));
+    hooks::run_hooks(&config.hooks, flac_dir, verdict, &report)?;
+
+    if let Some(requirement) = config.thresholds.require {
+        if !report.meets(requirement) {
+            bail!("not every track meets the required accuracy");
+        }
+    }
+
+    Ok(())
+}
+
+fn run_check_log(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let rip_log = riplog::read_rip_log(matches.value_of("LOG").unwrap())?;
+    let album = validate::identify_album(matches.value_of("FLAC_DIR").unwrap())?;
+
+    let frame_lengths = album.track_files.iter().map(offset::get_frame_lengths).collect::<Result<Vec<_>, _>>()?;
+    let selection = ChecksumSelection { crc32: true, ..ChecksumSelection::none() };
+    let progress = progress_listener(matches);
+    let local_tracks = validate::calc_track_crcs(&config.decoder, &album.track_files, selection, &*progress);
+    progress.finish();
+    let local_tracks = local_tracks?;
+
+    let comparison = riplog::compare_rip_log(&rip_log, &frame_lengths, &local_tracks);
+
+    let describe = |m: Option<bool>| match m {
+        Some(true) => "match",
+        Some(false) => "MISMATCH",
+        None => "n/a",
+    };
+
+    println!("Log format: {:?}", rip_log.kind);
+    if let Some(read_offset) = rip_log.read_offset {
+        println!("Read offset: {}", read_offset);
+    }
+    if !comparison.track_count_matches {
+        println!("Track count MISMATCH: log has {}, found {} files", rip_log.toc.len(), album.track_files.len());
+    }
+
+    for track in &comparison.tracks {
+        println!(
+            "Track {:>2}  length {}  AR v1 {}  AR v2 {}  test CRC {}  copy CRC {}",
+            track.track_num,
+            describe(track.length_matches),
+            describe(track.ar_v1_matches),
+            describe(track.ar_v2_matches),
+            describe(track.test_crc_matches),
+            describe(track.copy_crc_matches),
+        );
+
+        if track.test_matches_copy == Some(false) {
+            println!("          test and copy CRCs in the log differ, so the track was not read reliably");
+        }
+    }
+
+    if comparison.is_consistent() {
+        println!("Files match the rip log");
+        Ok(())
+    }
+    else {
+        bail!("files do not match the rip log");
+    }
+}
+
+/// Reads the TOC given with `--toc` or `--toc-file`, if any.
+fn toc_from_args(matches: &ArgMatches) -> Result<Option<toc::Toc>, failure::Error> {
+    let toc_text = match (matches.value_of("toc"), matches.value_of("toc_file")) {
+        (Some(toc_text), _) => toc_text.to_string(),
+        (None, Some(toc_file)) => riplog::read_log_text(toc_file)?,
+        (None, None) => return Ok(None),
+    };
+
+    Ok(Some(toc::parse_toc(&toc_text)?))
+}
+
+/// Pairs the files of an incomplete disc with their track numbers, given with `--tracks` or taken from the file names.
+fn partial_tracks(flac_dir: &str, track_nums: Option<&str>) -> Result<Vec<(u8, PathBuf)>, failure::Error> {
+    let flac_files = discovery::get_flac_files_in_dir(flac_dir)?;
+
+    match track_nums {
+        Some(track_nums) => {
+            let track_nums = track_nums.split(',')
+                .map(|t| t.trim().parse::<u8>().map_err(|_| format_err!("invalid track number: {}", t)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if track_nums.len() != flac_files.len() {
+                bail!("{} track numbers given for {} files", track_nums.len(), flac_files.len());
+            }
+
+            Ok(track_nums.into_iter().zip(flac_files).collect())
+        },
+        None => discovery::number_tracks_by_file_name(flac_files),
+    }
+}
+
+fn run_lookup(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let disc_info = toc_from_args(matches)?.ok_or_else(|| format_err!("no TOC given"))?.disc_info()?;
+    let fetcher = config.fetcher()?;
+    let url = fetcher.ar_bin_url(&disc_info);
+
+    println!("Disc ID: {}", disc_info.ar_disc_id());
+    println!("dBAR URL: {}", url);
+
+    if matches.is_present("no_fetch") {
+        return Ok(());
+    }
+
+    let ar_bin_data = match fetcher.get_ar_bin(&disc_info)? {
+        Some(ar_bin_data) => ar_bin_data,
+        None => {
+            println!("Disc not present in AccurateRip database");
+            return Ok(());
+        },
+    };
+
+    for (pressing_index, (_, track_results)) in fetch::unpack_ar_bin(&ar_bin_data)?.into_iter().enumerate() {
+        println!();
+        println!("Pressing {}", pressing_index);
+
+        for (track_index, track_result) in track_results.iter().enumerate() {
+            println!(
+                "  Track {:>2}  [{:0>8X}]  (confidence {})",
+                track_index + 1, track_result.crc, track_result.confidence,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run_correct(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let flac_dir = matches.value_of("FLAC_DIR").unwrap();
+    let output_dir = matches.value_of("OUTPUT_DIR").unwrap();
+
+    let offset = match matches.value_of("offset") {
+        Some(offset) => Some(offset.parse::<i32>().map_err(|_| format_err!("invalid offset: {}", offset))?),
+        None => None,
+    };
+
+    let fetcher = config.fetcher()?;
+    let progress = progress_listener(matches);
+    let correction = correct::correct(flac_dir, output_dir, offset, ChecksumSelection::all(), config, &fetcher, &*progress);
+    progress.finish();
+    let correction = correction?;
+
+    // Written to stderr to keep stdout parseable when using JSON output.
+    eprintln!("Shifted audio by {} samples into {} files", correction.offset, correction.files.len());
+
+    println!("{}", format::render(&correction.report, config.output.format)?);
+
+    Ok(())
+}
+
+fn run_mirror(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let (name, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
+    let config = load_config(sub_matches)?;
+
+    let mirror = config.mirror()?;
+
+    if name == "add" {
+        return run_mirror_add(sub_matches, &config, &mirror);
+    }
+
+    let library_discs = mirror::scan_library(sub_matches.value_of("LIBRARY_DIR").unwrap())?;
+
+    match name {
+        "sync" => run_mirror_sync(sub_matches, &config, &mirror, library_discs),
+        "missing" => run_mirror_missing(&mirror, library_discs),
+        "verify" => verify_library(&config, &mirror, library_discs, false, &*progress_listener(sub_matches)),
+        _ => unreachable!(),
+    }
+}
+
+fn run_mirror_add(matches: &ArgMatches, config: &Config, mirror: &Mirror) -> Result<(), failure::Error> {
+    let album = validate::identify_album(matches.value_of("FLAC_DIR").unwrap())?;
+    let progress = progress_listener(matches);
+    let local_tracks = validate::calc_track_crcs(&config.decoder, &album.track_files, ChecksumSelection::none(), &*progress);
+    progress.finish();
+    let local_tracks = local_tracks?;
+
+    if let Some(track) = local_tracks.iter().find(|t| t.md5_check == decode::Md5Check::Mismatch) {
+        bail!("{} is corrupted, its audio does not match its MD5 signature", track.path.display());
+    }
+
+    let crcs = local_tracks.iter().map(|t| t.crcs).collect::<Vec<_>>();
+
+    match mirror::add_rip(mirror, &album.disc_info, &crcs)? {
+        MergeOutcome::Confirmed { pressing } => println!("Confirmed pressing {} of disc {}", pressing, album.disc_info.ar_disc_id()),
+        MergeOutcome::Added { pressing } => println!("Added pressing {} of disc {}", pressing, album.disc_info.ar_disc_id()),
+    }
+
+    Ok(())
+}
+
+fn run_mirror_sync(matches: &ArgMatches, config: &Config, mirror: &Mirror, library_discs: Vec<mirror::LibraryDisc>) -> Result<(), failure::Error> {
+    let refresh = matches.is_present("refresh");
+    let mut num_failed = 0;
+
+    let pending_discs = library_discs.iter()
+        .filter_map(|d| d.disc_info.as_ref().ok())
+        .filter(|d| refresh || !mirror.contains(d))
+        .cloned()
+        .collect::<Vec<_>>();
+    let prefetched = PrefetchedBins::fetch(&config.fetcher()?, &pending_discs, config.network.max_in_flight_requests)?;
+
+    for library_disc in library_discs {
+        let outcome = library_disc.disc_info.and_then(|disc_info| mirror::sync_disc(mirror, &prefetched, &disc_info, refresh));
+
+        match outcome {
+            Ok(SyncOutcome::AlreadyPresent) => println!("present     {}", library_disc.album_dir.display()),
+            Ok(SyncOutcome::Downloaded) => println!("downloaded  {}", library_disc.album_dir.display()),
+            Ok(SyncOutcome::NotInDatabase) => println!("not found   {}", library_disc.album_dir.display()),
+            Err(err) => {
+                eprintln!("error: {}: {}", library_disc.album_dir.display(), err);
+                num_failed += 1;
+            },
+        }
+    }
+
+    if num_failed > 0 {
+        bail!("unable to sync {} discs", num_failed);
+    }
+
+    Ok(())
+}
+
+fn run_mirror_missing(mirror: &Mirror, library_discs: Vec<mirror::LibraryDisc>) -> Result<(), failure::Error> {
+    let mut num_missing = 0;
+
+    for library_disc in library_discs {
+        match library_disc.disc_info {
+            Ok(ref disc_info) if mirror.contains(disc_info) => {},
+            Ok(ref disc_info) => {
+                println!("{}  {}", disc_info.ar_disc_id(), library_disc.album_dir.display());
+                num_missing += 1;
+            },
+            Err(err) => eprintln!("error: {}: {}", library_disc.album_dir.display(), err),
+        }
+    }
+
+    if num_missing > 0 {
+        bail!("{} discs are missing from the mirror", num_missing);
+    }
+
+    Ok(())
+}
+
+/// Validates every disc of a library against dBAR files from `source`, printing one line per disc.
+fn verify_library(
+    config: &Config,
+    source: &BinSource,
+    library_discs: Vec<mirror::LibraryDisc>,
+    write_reports: bool,
+    progress: &ProgressListener,
+) -> Result<(), failure::Error> {
+    let requirement = config.thresholds.require.unwrap_or(validate::AccuracyRequirement::Any);
+    let selection = if write_reports { ChecksumSelection::all() } else { ChecksumSelection::none() };
+    let mut num_failed = 0;
+
+    for library_disc in library_discs {
+        let report = validate::validate(&library_disc.album_dir, selection, config, source, progress);
+        progress.finish();
+
+        // Reports are archived next to each album, whatever the outcome of the validation.
+        let report = match (report, write_reports) {
+            (Ok(report), true) => validate::identify_album(&library_disc.album_dir)
+                .and_then(|album| report::save_report(library_disc.album_dir.join(report::REPORT_FILE_NAME), &album.toc, &report, config.report.key()))
+                .map(|_| report),
+            (report, _) => report,
+        };
+
+        let report = report.and_then(|report| {
+            let verdict = validate::Verdict::for_report(&report, requirement);
+            hooks::run_hooks(&config.hooks, &library_disc.album_dir, verdict, &report).map(|_| report)
+        });
+
+        match report {
+            Ok(ref report) if report.meets(requirement) => println!("accurate      {}", library_disc.album_dir.display()),
+            Ok(_) => {
+                println!("NOT ACCURATE  {}", library_disc.album_dir.display());
+                num_failed += 1;
+            },
+            Err(err) => {
+                eprintln!("error: {}: {}", library_disc.album_dir.display(), err);
+                num_failed += 1;
+            },
+        }
+    }
+
+    if num_failed > 0 {
+        bail!("{} discs could not be verified as accurate", num_failed);
+    }
+
+    Ok(())
+}
+
+fn run_check_report(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let mut num_failed = 0;
+
+    for report_path in matches.values_of("REPORT").unwrap() {
+        // Invalid UTF-8 from corruption is replaced rather than rejected, so that it shows up as a checksum mismatch.
+        let check = fs::read(report_path)
+            .map_err(failure::Error::from)
+            .and_then(|bytes| report::check_report(&String::from_utf8_lossy(&bytes), config.report.key()));
+
+        match check {
+            Ok(report::ReportCheck::Valid) => println!("OK        {}", report_path),
+            Ok(report::ReportCheck::Unsigned) => {
+                println!("UNSIGNED  {}", report_path);
+                num_failed += 1;
+            },
+            Ok(report::ReportCheck::Modified) => {
+                println!("MODIFIED  {}", report_path);
+                num_failed += 1;
+            },
+            Err(err) => {
+                eprintln!("error: {}: {}", report_path, err);
+                num_failed += 1;
+            },
+        }
+    }
+
+    if num_failed > 0 {
+        bail!("{} reports do not match their checksum, or are not signed", num_failed);
+    }
+
+    Ok(())
+}
+
+fn run_batch(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let library_discs = mirror::scan_library(matches.value_of("LIBRARY_DIR").unwrap())?;
+
+    // Fetching every dBAR file up front overlaps the requests, instead of waiting on each one between albums.
+    let discs = library_discs.iter().filter_map(|d| d.disc_info.as_ref().ok()).cloned().collect::<Vec<_>>();
+    let prefetched = PrefetchedBins::fetch(&config.fetcher()?, &discs, config.network.max_in_flight_requests)?;
+
+    verify_library(config, &prefetched, library_discs, matches.is_present("write_reports"), &*progress_listener(matches))
+}
+
+fn run_reverify(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let reference = if matches.is_present("from_tags") { reverify::Reference::Tags } else { reverify::Reference::Report };
+    let album_dirs = discovery::find_album_dirs(matches.value_of("LIBRARY_DIR").unwrap())?;
+    let progress = progress_listener(matches);
+    let mut num_failed = 0;
+
+    for album_dir in album_dirs {
+        let results = reverify::reverify(&album_dir, reference, config, &*progress);
+        progress.finish();
+
+        let results = match results {
+            Ok(results) => results,
+            Err(err) => {
+                eprintln!("error: {}: {}", album_dir.display(), err);
+                num_failed += 1;
+                continue;
+            },
+        };
+
+        // Files added after verification cannot be checked, but do not mean that any audio changed.
+        for result in results.iter().filter(|r| r.status == reverify::IntegrityStatus::NotRecorded) {
+            warn!("{}: no CRCs were recorded for this file", result.path.display());
+        }
+
+        if results.iter().any(|r| r.status.is_changed()) {
+            println!("CHANGED       {}", album_dir.display());
+
+            for result in results.iter().filter(|r| r.status.is_changed()) {
+                let file_name = result.path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default();
+                println!("    {:<12}  {}", result.status.description(), file_name);
+            }
+
+            num_failed += 1;
+        }
+        else {
+            println!("unchanged     {}", album_dir.display());
+        }
+    }
+
+    if num_failed > 0 {
+        bail!("{} discs changed since they were verified, or could not be re-verified", num_failed);
+    }
+
+    Ok(())
+}
+
+fn run_watch(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let inbox_dir = matches.value_of("INBOX_DIR").unwrap();
+
+    let options = watch::WatchOptions {
+        action: match config.watch.move_to {
+            Some(ref dest_dir) => watch::Action::MoveTo(dest_dir.clone()),
+            None => watch::Action::Label,
+        },
+        settle: Duration::from_secs(config.watch.settle_secs),
+        poll_interval: Duration::from_secs(config.watch.poll_interval_secs.max(1)),
+        notifications: config.watch.notifications,
+    };
+
+    // One fetcher for the whole run, so that its rate limit covers every album.
+    let fetcher = config.fetcher()?;
+
+    eprintln!("Watching {} for new albums", inbox_dir);
+
+    watch::watch(inbox_dir, &options, config, &fetcher, &*progress_listener(matches))
+}
+
+fn run_serve(matches: &ArgMatches, config: &Config) -> Result<(), failure::Error> {
+    let listen = matches.value_of("listen").unwrap_or(&config.server.listen);
+    let accept_submissions = config.server.accept_submissions && !matches.is_present("read_only");
+
+    let server = server::Server::bind(listen, config.mirror()?, accept_submissions)?;
+    eprintln!("Serving dBAR files at http://{}{}", server.local_addr()?, server::URL_PREFIX);
+
+    server.serve()
+}
 
 fn main() {
     let matches = App::new("ar-flac-rs")
                     .version("1.0")
                     .about("Validates FLAC files against the online AccurateRip database")
+                    .setting(AppSettings::SubcommandsNegateReqs)
+                    .arg(
+                        Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .multiple(true)
+                        .global(true)
+                        .help("show more details of what is being done, repeat for even more")
+                    )
+                    .arg(
+                        Arg::with_name("quiet")
+                        .short("q")
+                        .long("quiet")
+                        .global(true)
+                        .conflicts_with("verbose")
+                        .help("only show errors")
+                    )
+                    .arg(
+                        Arg::with_name("config")
+                        .long("config")
+                        .takes_value(true)
+                        .global(true)
+                        .help("path to the configuration file [default: $XDG_CONFIG_HOME/ar-flac-rs/config.toml]")
+                    )
+                    .arg(
+                        Arg::with_name("accuraterip_url")
+                        .long("accuraterip-url")
+                        .takes_value(true)
+                        .global(true)
+                        .help("base URL of the AccurateRip database or a mirror of it")
+                    )
+                    .arg(
+                        Arg::with_name("timeout")
+                        .long("timeout")
+                        .takes_value(true)
+                        .global(true)
+                        .help("timeout in seconds for requests to the database [default: 30]")
+                    )
+                    .arg(
+                        Arg::with_name("retries")
+                        .long("retries")
+                        .takes_value(true)
+                        .global(true)
+                        .help("number of times to retry failed requests to the database [default: 3]")
+                    )
+                    .arg(
+                        Arg::with_name("proxy")
+                        .long("proxy")
+                        .takes_value(true)
+                        .global(true)
+                        .help("proxy for requests to the database [default: from the http_proxy family of environment variables]")
+                    )
+                    .arg(
+                        Arg::with_name("user_agent")
+                        .long("user-agent")
+                        .takes_value(true)
+                        .global(true)
+                        .help("User-Agent header sent to the database")
+                    )
+                    .arg(
+                        Arg::with_name("cache_dir")
+                        .long("cache-dir")
+                        .takes_value(true)
+                        .global(true)
+                        .help("directory to cache dBAR files in")
+                    )
+                    .arg(
+                        Arg::with_name("no_cache")
+                        .long("no-cache")
+                        .global(true)
+                        .help("always fetch dBAR files from the database")
+                    )
+                    .arg(
+                        Arg::with_name("mirror_dir")
+                        .long("mirror-dir")
+                        .takes_value(true)
+                        .global(true)
+                        .help("root directory of a local mirror of the AccurateRip database")
+                    )
+                    .arg(
+                        Arg::with_name("no_progress")
+                        .long("no-progress")
+                        .global(true)
+                        .help("do not show progress while decoding and checksumming")
+                    )
+                    .arg(
+                        Arg::with_name("decoder")
+                        .long("decoder")
+                        .takes_value(true)
+                        .possible_values(&["flac", "ffmpeg"])
+                        .global(true)
+                        .help("external program used to decode audio [default: flac]")
+                    )
+                    .arg(
+                        Arg::with_name("flac_path")
+                        .long("flac-path")
+                        .takes_value(true)
+                        .global(true)
+                        .help("path to the `flac` binary")
+                    )
+                    .arg(
+                        Arg::with_name("min_confidence")
+                        .long("min-confidence")
+                        .takes_value(true)
+                        .global(true)
+                        .help("smallest total confidence for a track to count as accurate [default: 1]")
+                    )
+                    .arg(
+                        Arg::with_name("high_confidence")
+                        .long("high-confidence")
+                        .takes_value(true)
+                        .global(true)
+                        .help("smallest total confidence for a track to count as accurate with high confidence [default: 10]")
+                    )
+                    .arg(
+                        Arg::with_name("hook")
+                        .long("hook")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("COMMAND")
+                        .global(true)
+                        .help("shell command to run after each album is verified, in addition to configured hooks")
+                    )
+                    .arg(
+                        Arg::with_name("strict_hooks")
+                        .long("strict-hooks")
+                        .global(true)
+                        .help("fail verification of an album if one of its hooks fails")
+                    )
                     .arg(
                         Arg::with_name("FLAC_DIR")
                         .help("path to directory of FLAC files to validate")
+                        .required(true)
+                    )
+                    .arg(
+                        Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["text", "json"])
+                        .help("output format of the validation report [default: text]")
+                    )
+                    .arg(
+                        Arg::with_name("toc")
+                        .long("toc")
+                        .takes_value(true)
+                        .conflicts_with("toc_file")
+                        .help("MusicBrainz TOC string of the disc, to verify only some of its tracks")
+                    )
+                    .arg(
+                        Arg::with_name("toc_file")
+                        .long("toc-file")
+                        .takes_value(true)
+                        .help("file containing a TOC string, or an EAC or XLD log, to verify only some tracks of the disc")
+                    )
+                    .group(ArgGroup::with_name("toc_source").args(&["toc", "toc_file"]))
+                    .arg(
+                        Arg::with_name("tracks")
+                        .long("tracks")
+                        .takes_value(true)
+                        .value_name("LIST")
+                        .requires("toc_source")
+                        .help("comma-separated track numbers of the files in name order, if not given by their names (requires a TOC)")
+                    )
+                    .arg(
+                        Arg::with_name("checksums")
+                        .long("checksums")
+                        .takes_value(true)
+                        .default_value("ar,crc32,crc32-skip-zero,peak")
+                        .help("comma-separated checksums to calculate: ar, crc32, crc32-skip-zero, peak")
+                    )
+                    .arg(
+                        Arg::with_name("require")
+                        .long("require")
+                        .takes_value(true)
+                        .possible_values(&["any", "high-confidence", "high-confidence-v2"])
+                        .help("exit with an error unless every track is at least this accurate")
+                    )
+                    .arg(
+                        Arg::with_name("report")
+                        .long("report")
+                        .takes_value(true)
+                        .help("write a verification report with a checksum to this file, for archiving next to the album")
+                    )
+                    .arg(
+                        Arg::with_name("tag")
+                        .long("tag")
+                        .help("write the verification results into the Vorbis comments of each FLAC file")
+                    )
+                    .arg(
+                        Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .requires("tag")
+                        .help("only show the Vorbis comments that would be written, without modifying any files")
+                    )
+                    .subcommand(
+                        SubCommand::with_name("check-log")
+                        .about("Checks that FLAC files still match the EAC, XLD or whipper log they were ripped with")
+                        .arg(
+                            Arg::with_name("LOG")
+                            .help("path to the rip log")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("FLAC_DIR")
+                            .help("path to directory of FLAC files described by the log")
+                            .required(true)
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("lookup")
+                        .about("Calculates disc IDs from a TOC and looks them up in the AccurateRip database")
+                        .arg(
+                            Arg::with_name("toc")
+                            .long("toc")
+                            .takes_value(true)
+                            .required_unless("toc_file")
+                            .conflicts_with("toc_file")
+                            .help("MusicBrainz TOC string or `cd-discid --musicbrainz` output")
+                        )
+                        .arg(
+                            Arg::with_name("toc_file")
+                            .long("toc-file")
+                            .takes_value(true)
+                            .help("file containing a TOC string, or an EAC or XLD log")
+                        )
+                        .arg(
+                            Arg::with_name("no_fetch")
+                            .long("no-fetch")
+                            .help("only print the disc IDs and dBAR URL, without querying the database")
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("correct")
+                        .about("Writes a copy of a rip with its sample offset corrected, and verifies the result")
+                        .arg(
+                            Arg::with_name("FLAC_DIR")
+                            .help("path to directory of FLAC files to correct")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("OUTPUT_DIR")
+                            .help("path to directory to write the corrected FLAC files into")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("offset")
+                            .long("offset")
+                            .takes_value(true)
+                            .allow_hyphen_values(true)
+                            .help("number of samples to shift the audio by, detected from the database if not given")
+                        )
+                        .arg(
+                            Arg::with_name("max_offset")
+                            .long("max-offset")
+                            .takes_value(true)
+                            .help("largest offset in samples to search for when detecting the offset [default: 2940]")
+                        )
+                        .arg(
+                            Arg::with_name("format")
+                            .long("format")
+                            .takes_value(true)
+                            .possible_values(&["text", "json"])
+                            .help("output format of the validation report [default: text]")
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("mirror")
+                        .about("Manages a local mirror of the AccurateRip database for a library")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            SubCommand::with_name("sync")
+                            .about("Downloads the dBAR file of every disc in a library into the mirror")
+                            .arg(
+                                Arg::with_name("LIBRARY_DIR")
+                                .help("path to a directory of albums, searched recursively")
+                                .required(true)
+                            )
+                            .arg(
+                                Arg::with_name("refresh")
+                                .long("refresh")
+                                .help("download dBAR files again even if already in the mirror")
+                            )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("missing")
+                            .about("Lists discs in a library whose dBAR file is not in the mirror")
+                            .arg(
+                                Arg::with_name("LIBRARY_DIR")
+                                .help("path to a directory of albums, searched recursively")
+                                .required(true)
+                            )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("add")
+                            .about("Records the CRCs of a rip in the mirror, incrementing the confidence of matching pressings")
+                            .arg(
+                                Arg::with_name("FLAC_DIR")
+                                .help("path to directory of FLAC files of a verified rip")
+                                .required(true)
+                            )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("verify")
+                            .about("Validates every disc in a library against the mirror, without querying the database")
+                            .arg(
+                                Arg::with_name("LIBRARY_DIR")
+                                .help("path to a directory of albums, searched recursively")
+                                .required(true)
+                            )
+                            .arg(
+                                Arg::with_name("require")
+                                .long("require")
+                                .takes_value(true)
+                                .possible_values(&["any", "high-confidence", "high-confidence-v2"])
+                                .help("count a disc as accurate only if every track is at least this accurate [default: any]")
+                            )
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("batch")
+                        .about("Validates every disc in a library, fetching dBAR files concurrently")
+                        .arg(
+                            Arg::with_name("LIBRARY_DIR")
+                            .help("path to a directory of albums, searched recursively")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("write_reports")
+                            .long("write-reports")
+                            .help("write a verification report into each album directory")
+                        )
+                        .arg(
+                            Arg::with_name("max_in_flight")
+                            .long("max-in-flight")
+                            .takes_value(true)
+                            .help("largest number of requests to the database at once [default: 4]")
+                        )
+                        .arg(
+                            Arg::with_name("require")
+                            .long("require")
+                            .takes_value(true)
+                            .possible_values(&["any", "high-confidence", "high-confidence-v2"])
+                            .help("count a disc as accurate only if every track is at least this accurate [default: any]")
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("check-report")
+                        .about("Checks that verification reports have not been corrupted since they were written, nor modified if they were signed with a report key")
+                        .arg(
+                            Arg::with_name("REPORT")
+                            .help("paths to report files")
+                            .required(true)
+                            .multiple(true)
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("reverify")
+                        .about("Checks that albums still have the CRCs recorded when they were verified, without querying the database")
+                        .arg(
+                            Arg::with_name("LIBRARY_DIR")
+                            .help("path to an album or a directory of albums, searched recursively")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("from_tags")
+                            .long("from-tags")
+                            .help("read the recorded CRCs from the tags written by --tag, instead of each album's report")
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("watch")
+                        .about("Watches an inbox directory, and validates each album once its files stop changing")
+                        .arg(
+                            Arg::with_name("INBOX_DIR")
+                            .help("path to the directory that albums are added to, watched recursively")
+                            .required(true)
+                        )
+                        .arg(
+                            Arg::with_name("move_to")
+                            .long("move-to")
+                            .takes_value(true)
+                            .value_name("DIR")
+                            .help("move validated albums into DIR/accurate, DIR/inaccurate or DIR/unknown instead of tagging them")
+                        )
+                        .arg(
+                            Arg::with_name("settle")
+                            .long("settle")
+                            .takes_value(true)
+                            .value_name("SECS")
+                            .help("how long the files of an album must stay unchanged before it is validated [default: 30]")
+                        )
+                        .arg(
+                            Arg::with_name("poll")
+                            .long("poll")
+                            .help("scan the inbox periodically instead of using file system notifications")
+                        )
+                        .arg(
+                            Arg::with_name("poll_interval")
+                            .long("poll-interval")
+                            .takes_value(true)
+                            .value_name("SECS")
+                            .help("interval between scans when polling [default: 10]")
+                        )
+                        .arg(
+                            Arg::with_name("require")
+                            .long("require")
+                            .takes_value(true)
+                            .possible_values(&["any", "high-confidence", "high-confidence-v2"])
+                            .help("count an album as accurate only if every track is at least this accurate [default: any]")
+                        )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("serve")
+                        .about("Serves the local mirror over HTTP at the same URLs as the AccurateRip database")
+                        .arg(
+                            Arg::with_name("listen")
+                            .long("listen")
+                            .takes_value(true)
+                            .help("address and port to listen on [default: 127.0.0.1:8080]")
+                        )
+                        .arg(
+                            Arg::with_name("read_only")
+                            .long("read-only")
+                            .help("reject submissions of rip results")
+                        )
                     )
                     .get_matches();
 
-    println!("Using FLAC_DIR: {}", matches.value_of("FLAC_DIR").unwrap());
+    let (verbose, quiet) = verbosity(&matches);
+
+    if let Err(err) = logging::init(logging::level_for_verbosity(verbose, quiet)) {
+        eprintln!("error: unable to set up logging: {}", err);
+    }
+
+    let result = match matches.subcommand() {
+        ("mirror", Some(sub_matches)) => run_mirror(sub_matches),
+        (name, Some(sub_matches)) => load_config(sub_matches).and_then(|config| match name {
+            "check-log" => run_check_log(sub_matches, &config),
+            "lookup" => run_lookup(sub_matches, &config),
+            "correct" => run_correct(sub_matches, &config),
+            "batch" => run_batch(sub_matches, &config),
+            "check-report" => run_check_report(sub_matches, &config),
+            "reverify" => run_reverify(sub_matches, &config),
+            "watch" => run_watch(sub_matches, &config),
+            "serve" => run_serve(sub_matches, &config),
+            _ => unreachable!(),
+        }),
+        _ => load_config(&matches).and_then(|config| run_validate(&matches, &config)),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,311 @@
+//! Manages a local mirror of dBAR files, laid out in the same directory structure as the AccurateRip database.
+//! A mirror can be served to other machines as is, or used in place of the database.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use failure;
+
+use offset::DiscInfo;
+use crc::CRCs;
+use fetch::ar_bin_path;
+use fetch::BinSource;
+use discovery::find_album_dirs;
+use validate::identify_album;
+use submit::merge_submission;
+use submit::MergeOutcome;
+use util::write_atomically;
+
+/// How long to wait for another writer to release a dBAR file.
+const LOCK_TIMEOUT_SECS: u64 = 30;
+
+/// Lock files older than this were left behind by a writer that died, since no write takes nearly as long.
+const STALE_LOCK_SECS: u64 = 300;
+
+const LOCK_RETRY_MILLIS: u64 = 50;
+
+/// An advisory lock on a dBAR file of a mirror, held as long as its lock file exists.
+/// Creating a file that must not exist yet is atomic even on network file systems,
+/// so this also keeps out writers in other processes, or on other hosts sharing the mirror.
+pub struct BinLock {
+    lock_path: PathBuf,
+}
+
+impl BinLock {
+    fn acquire(bin_path: &Path) -> Result<Self, failure::Error> {
+        let lock_path = bin_path.with_extension("bin.lock");
+        let start = Instant::now();
+
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(_) => return Ok(BinLock { lock_path }),
+                Err(ref err) if err.kind() == ErrorKind::AlreadyExists => {},
+                Err(err) => bail!("unable to create {}: {}", lock_path.display(), err),
+            }
+
+            let lock_age = fs::metadata(&lock_path)
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default());
+
+            if let Ok(lock_age) = lock_age {
+                if lock_age >= Duration::from_secs(STALE_LOCK_SECS) {
+                    warn!("removing stale lock {}", lock_path.display());
+                    let _ = fs::remove_file(&lock_path);
+                    continue;
+                }
+            }
+
+            if start.elapsed() >= Duration::from_secs(LOCK_TIMEOUT_SECS) {
+                bail!("timed out waiting for {} to be released by another writer", lock_path.display());
+            }
+
+            thread::sleep(Duration::from_millis(LOCK_RETRY_MILLIS));
+        }
+    }
+}
+
+impl Drop for BinLock {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.lock_path) {
+            warn!("unable to remove {}: {}", self.lock_path.display(), err);
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Mirror {
+    dir: PathBuf,
+}
+
+impl Mirror {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Mirror { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn bin_path(&self, disc_info: &DiscInfo) -> PathBuf {
+        ar_bin_path(disc_info).split('/').fold(self.dir.clone(), |path, component| path.join(component))
+    }
+
+    pub fn contains(&self, disc_info: &DiscInfo) -> bool {
+        self.bin_path(disc_info).is_file()
+    }
+
+    pub fn put(&self, disc_info: &DiscInfo, ar_bin_data: &[u8]) -> Result<(), failure::Error> {
+        let bin_path = self.bin_path(disc_info);
+
+        if let Some(parent) = bin_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Readers and writers of a shared mirror never see each other's partial files.
+        write_atomically(&bin_path, ar_bin_data)
+    }
+
+    /// Locks the dBAR file of a disc against other writers, until the returned lock is dropped.
+    /// Only needed to update a file based on its contents, since `put` alone is atomic.
+    pub fn lock(&self, disc_info: &DiscInfo) -> Result<BinLock, failure::Error> {
+        let bin_path = self.bin_path(disc_info);
+
+        if let Some(parent) = bin_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        BinLock::acquire(&bin_path)
+    }
+}
+
+impl BinSource for Mirror {
+    fn get_ar_bin(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+        let bin_path = self.bin_path(disc_info);
+
+        if !bin_path.is_file() {
+            return Ok(None);
+        }
+
+        let mut ar_bin_data = vec![];
+        File::open(&bin_path)?.read_to_end(&mut ar_bin_data)?;
+
+        Ok(Some(ar_bin_data))
+    }
+}
+
+/// A disc found in a library, or the reason it could not be identified.
+pub struct LibraryDisc {
+    pub album_dir: PathBuf,
+    pub disc_info: Result<DiscInfo, failure::Error>,
+}
+
+/// Finds and identifies every disc in a library.
+/// Discs that cannot be identified are returned along with the error, so that one bad album does not stop a scan.
+pub fn scan_library<P: AsRef<Path>>(library_dir: P) -> Result<Vec<LibraryDisc>, failure::Error> {
+    let album_dirs = find_album_dirs(library_dir)?;
+
+    Ok(album_dirs.into_iter().map(|album_dir| {
+        let disc_info = identify_album(&album_dir).map(|album| album.disc_info);
+        LibraryDisc { album_dir, disc_info }
+    }).collect())
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncOutcome {
+    AlreadyPresent,
+    Downloaded,
+    NotInDatabase,
+}
+
+/// Makes sure the mirror holds the dBAR file for a disc, fetching it from `source` if needed.
+/// With `refresh`, files already in the mirror are fetched again, since new submissions change them over time.
+pub fn sync_disc(mirror: &Mirror, source: &BinSource, disc_info: &DiscInfo, refresh: bool) -> Result<SyncOutcome, failure::Error> {
+    if !refresh && mirror.contains(disc_info) {
+        return Ok(SyncOutcome::AlreadyPresent);
+    }
+
+    match source.get_ar_bin(disc_info)? {
+        Some(ar_bin_data) => {
+            mirror.put(disc_info, &ar_bin_data)?;
+            Ok(SyncOutcome::Downloaded)
+        },
+        None => Ok(SyncOutcome::NotInDatabase),
+    }
+}
+
+/// Records the CRCs of a rip in the mirror, as if they had been submitted to the database.
+/// The dBAR file stays locked from reading until writing it, so that no concurrent submission is lost.
+pub fn add_rip(mirror: &Mirror, disc_info: &DiscInfo, crcs: &[CRCs]) -> Result<MergeOutcome, failure::Error> {
+    let _lock = mirror.lock(disc_info)?;
+    let ar_bin_data = mirror.get_ar_bin(disc_info)?.unwrap_or_default();
+    let (merged, outcome) = merge_submission(&ar_bin_data, disc_info, crcs)?;
+
+    mirror.put(disc_info, &merged)?;
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::cell::RefCell;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    use failure;
+
+    use self::tempfile::tempdir;
+
+    use offset::DiscInfo;
+    use offset::SAMPLE_DISC_INFO;
+    use crc::CRCs;
+    use fetch::unpack_ar_bin;
+    use fetch::BinSource;
+    use submit::MergeOutcome;
+
+    use super::add_rip;
+    use super::sync_disc;
+    use super::Mirror;
+    use super::SyncOutcome;
+
+    struct MockSource {
+        ar_bin_data: Option<Vec<u8>>,
+        num_requests: RefCell<usize>,
+    }
+
+    impl BinSource for MockSource {
+        fn get_ar_bin(&self, _disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+            *self.num_requests.borrow_mut() += 1;
+            Ok(self.ar_bin_data.clone())
+        }
+    }
+
+    #[test]
+    fn test_mirror() {
+        let dir = tempdir().unwrap();
+        let mirror = Mirror::new(dir.path());
+
+        let expected_path = dir.path().join("f").join("a").join("a").join("dBAR-010-0012baaf-0094edfd-8b0b500a.bin");
+        assert_eq!(expected_path, mirror.bin_path(&SAMPLE_DISC_INFO));
+
+        assert!(!mirror.contains(&SAMPLE_DISC_INFO));
+        assert_eq!(None, mirror.get_ar_bin(&SAMPLE_DISC_INFO).unwrap());
+
+        mirror.put(&SAMPLE_DISC_INFO, &[1, 2, 3]).unwrap();
+
+        assert!(mirror.contains(&SAMPLE_DISC_INFO));
+        assert!(expected_path.is_file());
+        assert_eq!(Some(vec![1, 2, 3]), mirror.get_ar_bin(&SAMPLE_DISC_INFO).unwrap());
+    }
+
+    #[test]
+    fn test_sync_disc() {
+        let dir = tempdir().unwrap();
+        let mirror = Mirror::new(dir.path());
+
+        let missing_source = MockSource { ar_bin_data: None, num_requests: RefCell::new(0) };
+        assert_eq!(SyncOutcome::NotInDatabase, sync_disc(&mirror, &missing_source, &SAMPLE_DISC_INFO, false).unwrap());
+        assert!(!mirror.contains(&SAMPLE_DISC_INFO));
+
+        let source = MockSource { ar_bin_data: Some(vec![4, 5]), num_requests: RefCell::new(0) };
+        assert_eq!(SyncOutcome::Downloaded, sync_disc(&mirror, &source, &SAMPLE_DISC_INFO, false).unwrap());
+        assert_eq!(SyncOutcome::AlreadyPresent, sync_disc(&mirror, &source, &SAMPLE_DISC_INFO, false).unwrap());
+        assert_eq!(1, *source.num_requests.borrow());
+
+        assert_eq!(SyncOutcome::Downloaded, sync_disc(&mirror, &source, &SAMPLE_DISC_INFO, true).unwrap());
+        assert_eq!(2, *source.num_requests.borrow());
+        assert_eq!(Some(vec![4, 5]), mirror.get_ar_bin(&SAMPLE_DISC_INFO).unwrap());
+    }
+
+    #[test]
+    fn test_add_rip() {
+        let dir = tempdir().unwrap();
+        let mirror = Mirror::new(dir.path());
+        let disc_info = DiscInfo { num_tracks: 2, ..SAMPLE_DISC_INFO };
+        let crcs = vec![CRCs { v1: 1, v2: 2 }, CRCs { v1: 3, v2: 4 }];
+
+        assert_eq!(MergeOutcome::Added { pressing: 0 }, add_rip(&mirror, &disc_info, &crcs).unwrap());
+        assert_eq!(MergeOutcome::Confirmed { pressing: 0 }, add_rip(&mirror, &disc_info, &crcs).unwrap());
+        assert!(mirror.contains(&disc_info));
+    }
+
+    #[test]
+    fn test_add_rip_concurrent() {
+        let dir = tempdir().unwrap();
+        let mirror = Arc::new(Mirror::new(dir.path()));
+        let disc_info = DiscInfo { num_tracks: 2, ..SAMPLE_DISC_INFO };
+
+        // Each thread submits a different pressing, so a lost update would leave out one of them.
+        let handles = (0..8u32).map(|i| {
+            let mirror = Arc::clone(&mirror);
+            thread::spawn(move || {
+                let crcs = vec![CRCs { v1: i, v2: i }, CRCs { v1: i, v2: i }];
+                add_rip(&mirror, &disc_info, &crcs).unwrap()
+            })
+        }).collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let ar_bin_data = mirror.get_ar_bin(&disc_info).unwrap().unwrap();
+        assert_eq!(8, unpack_ar_bin(&ar_bin_data).unwrap().len());
+
+        // The lock file is gone once the lock is released.
+        let lock_path = mirror.bin_path(&disc_info).with_extension("bin.lock");
+        assert!(!lock_path.exists());
+
+        let lock = mirror.lock(&disc_info).unwrap();
+        assert!(lock_path.exists());
+        drop(lock);
+        assert!(!lock_path.exists());
+        assert_eq!(1, fs::read_dir(lock_path.parent().unwrap()).unwrap().count());
+    }
+}
@@ -8,7 +8,6 @@ use failure::Error;
 use std::cmp;
 
 use util::sum_digits;
-use util::LookaheadExt;
 
 const SAMPLES_PER_SECOND: u64 = 44100;
 const SAMPLES_PER_FRAME: u64 = 588;  // 44100 / 75
@@ -17,7 +16,7 @@ const FRAMES_PER_SECOND: u64 = 75;
 pub type FrameLength = u64;
 pub type FrameOffset = u64;
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
 pub struct DiscInfo {
     pub id_1: u32,
     pub id_2: u32,
@@ -25,7 +24,42 @@ pub struct DiscInfo {
     pub num_tracks: u8,
 }
 
+/// Disc info of the sample dBAR file in `test_util` (see `test_util::sample_bin_path`).
+#[cfg(test)]
+pub const SAMPLE_DISC_INFO: DiscInfo = DiscInfo {
+    id_1: 1227439,
+    id_2: 9760253,
+    cddb_id: 2332774410,
+    num_tracks: 10,
+};
+
+impl DiscInfo {
+    /// Formats the disc info the way it appears in dBAR file names, e.g. `010-0012baaf-0094edfd-8b0b500a`.
+    pub fn ar_disc_id(&self) -> String {
+        format!("{:0>3}-{:0>8x}-{:0>8x}-{:0>8x}", self.num_tracks, self.id_1, self.id_2, self.cddb_id)
+    }
+
+    /// Parses a disc ID in the format returned by `ar_disc_id`.
+    pub fn from_ar_disc_id(ar_disc_id: &str) -> Result<Self, Error> {
+        let fields = ar_disc_id.split('-').collect::<Vec<_>>();
+        let invalid = || format_err!("invalid disc ID: {}", ar_disc_id);
+
+        match fields.as_slice() {
+            &[num_tracks, id_1, id_2, cddb_id] if num_tracks.len() == 3 && [id_1, id_2, cddb_id].iter().all(|f| f.len() == 8) => {
+                Ok(DiscInfo {
+                    id_1: u32::from_str_radix(id_1, 16).map_err(|_| invalid())?,
+                    id_2: u32::from_str_radix(id_2, 16).map_err(|_| invalid())?,
+                    cddb_id: u32::from_str_radix(cddb_id, 16).map_err(|_| invalid())?,
+                    num_tracks: num_tracks.parse().map_err(|_| invalid())?,
+                })
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
 pub fn get_frame_lengths<P: AsRef<Path>>(flac_path: P) -> Result<u64, Error> {
+    let flac_path = flac_path.as_ref();
     let flac_tag = Tag::read_from_path(flac_path)?;
 
     let info_blocks = flac_tag.get_blocks(BlockType::StreamInfo);
@@ -36,6 +70,8 @@ pub fn get_frame_lengths<P: AsRef<Path>>(flac_path: P) -> Result<u64, Error> {
         let num_frames = (num_samples / SAMPLES_PER_FRAME)
                         + (if num_samples % SAMPLES_PER_FRAME == 0 {0} else {1});
 
+        debug!("{}: {} samples, {} frames", flac_path.display(), num_samples, num_frames);
+
         Ok(num_frames)
     }
     else {
@@ -56,51 +92,51 @@ pub fn calc_frame_offsets<II: IntoIterator<Item = FrameLength>>(frame_lengths: I
 }
 
 pub fn calc_disc_info<II: IntoIterator<Item = FrameOffset>>(frame_offsets: II) -> DiscInfo {
-    let mut id_1: u64 = 0;
-    let mut id_2: u64 = 1;
-    let mut cddb_id: u64 = 2;
+    // Track 1 is assumed to start at frame 0, and the last offset is the lead-out.
+    let mut track_offsets = vec![0];
+    track_offsets.extend(frame_offsets);
+
+    let leadout = track_offsets.pop().unwrap_or(0);
 
-    let mut track_count: u8 = 0;
+    calc_disc_info_for_track_offsets(&track_offsets, leadout)
+}
 
-    for (lookahead_pos, frame_offset) in frame_offsets.into_iter().lookahead() {
-        // println!("{}, {}, {}", id_1, id_2, cddb_id);
-        track_count += 1;
+/// Calculates disc IDs from the start offset of each track and the lead-out offset.
+/// Unlike `calc_disc_info`, this supports discs where track 1 does not start at frame 0,
+/// such as those with hidden track one audio in the pregap.
+pub fn calc_disc_info_for_track_offsets(track_offsets: &[FrameOffset], leadout: FrameOffset) -> DiscInfo {
+    let mut id_1: u64 = 0;
+    let mut id_2: u64 = 0;
+    let mut cddb_sum: u64 = 0;
 
+    let track_count = track_offsets.len() as u8;
+
+    for (track_index, &frame_offset) in track_offsets.iter().enumerate() {
         id_1 += frame_offset;
-        id_2 += cmp::max(frame_offset, 1u64) * (track_count + 1) as u64;
+        id_2 += cmp::max(frame_offset, 1u64) * (track_index + 1) as u64;
 
-        // If this is not the last frame offset, adjust the CDDB id.
-        if !lookahead_pos.is_end() {
-            cddb_id += sum_digits(frame_offset / FRAMES_PER_SECOND + 2);
-        }
-        else {
-            cddb_id = ((cddb_id % 255) << 24)
-                    + ((frame_offset / FRAMES_PER_SECOND) << 8)
-                    + track_count as u64;
-        }
+        // The CDDB ID uses absolute disc positions, which include the 2 second lead-in.
+        cddb_sum += sum_digits(frame_offset / FRAMES_PER_SECOND + 2);
     }
-    // println!("{}, {}, {}", id_1, id_2, cddb_id);
 
-    id_1 &= 0xFFFFFFFF;
-    id_2 &= 0xFFFFFFFF;
-    cddb_id &= 0xFFFFFFFF;
+    id_1 += leadout;
+    id_2 += cmp::max(leadout, 1u64) * (track_count + 1) as u64;
+
+    let first_offset = track_offsets.first().cloned().unwrap_or(0);
+    let disc_seconds = leadout / FRAMES_PER_SECOND - first_offset / FRAMES_PER_SECOND;
+
+    let cddb_id = ((cddb_sum % 255) << 24) + (disc_seconds << 8) + track_count as u64;
+
+    debug!("track offsets {:?}, lead-out {}", track_offsets, leadout);
 
     DiscInfo {
-        id_1: id_1 as u32,
-        id_2: id_2 as u32,
-        cddb_id: cddb_id as u32,
+        id_1: (id_1 & 0xFFFFFFFF) as u32,
+        id_2: (id_2 & 0xFFFFFFFF) as u32,
+        cddb_id: (cddb_id & 0xFFFFFFFF) as u32,
         num_tracks: track_count,
     }
 }
 
-pub fn calc_disc_info_for_files<P: AsRef<Path>, II: IntoIterator<Item = P>>(flac_paths: II) -> Result<DiscInfo, Error> {
-    let frame_lengths = flac_paths.into_iter().map(get_frame_lengths).collect::<Result<Vec<_>, _>>()?;
-    let frame_offsets = calc_frame_offsets(frame_lengths);
-    let disc_info = calc_disc_info(frame_offsets);
-
-    Ok(disc_info)
-}
-
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
@@ -110,6 +146,7 @@ mod tests {
     use super::get_frame_lengths;
     use super::calc_frame_offsets;
     use super::calc_disc_info;
+    use super::calc_disc_info_for_track_offsets;
     use super::DiscInfo;
 
     const EXPECTED_LENGTHS: &[FrameLength] = &[
@@ -217,4 +254,48 @@ mod tests {
             assert_eq!(expected, produced);
         }
     }
+
+    #[test]
+    fn test_calc_disc_info_for_track_offsets() {
+        let mut track_offsets = vec![0];
+        track_offsets.extend_from_slice(&EXPECTED_OFFSETS[..9]);
+        let leadout = EXPECTED_OFFSETS[9];
+
+        let expected = calc_disc_info(EXPECTED_OFFSETS.to_vec());
+        let produced = calc_disc_info_for_track_offsets(&track_offsets, leadout);
+        assert_eq!(expected, produced);
+
+        // Shifting the whole disc by a pregap moves every offset, including track 1's.
+        let pregap = 32;
+        let shifted: Vec<_> = track_offsets.iter().map(|o| o + pregap).collect();
+        let produced = calc_disc_info_for_track_offsets(&shifted, leadout + pregap);
+
+        assert_eq!(expected.num_tracks, produced.num_tracks);
+        assert_eq!(expected.id_1 + 11 * pregap as u32, produced.id_1);
+        // Track 1 contributes its offset instead of the minimum of 1.
+        assert_eq!(expected.id_2 - 1 + pregap as u32 + 65 * pregap as u32, produced.id_2);
+    }
+
+    #[test]
+    fn test_from_ar_disc_id() {
+        let disc_info = DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 10 };
+
+        assert_eq!(disc_info, DiscInfo::from_ar_disc_id("010-0012baaf-0094edfd-8b0b500a").unwrap());
+        assert_eq!(disc_info, DiscInfo::from_ar_disc_id(&disc_info.ar_disc_id()).unwrap());
+
+        let invalid_inputs = vec![
+            "",
+            "010-0012baaf-0094edfd",
+            "010-0012baaf-0094edfd-8b0b500a-00",
+            "10-0012baaf-0094edfd-8b0b500a",
+            "010-12baaf-0094edfd-8b0b500a",
+            "010-0012baaf-0094edfd-8b0b500g",
+            "999-0012baaf-0094edfd-8b0b500a",
+            "0+1-0012baaf-0094edfd-8b0b500a",
+        ];
+
+        for input in invalid_inputs {
+            assert!(DiscInfo::from_ar_disc_id(input).is_err());
+        }
+    }
 }
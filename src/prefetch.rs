@@ -0,0 +1,109 @@
+//! Fetches the dBAR files of many discs concurrently, so that network latency does not dominate when processing a library.
+
+use std::collections::HashMap;
+
+use failure;
+use rayon;
+use rayon::prelude::*;
+
+use offset::DiscInfo;
+use fetch::BinSource;
+
+/// dBAR files looked up ahead of time, which can then be used as a source for validating each album.
+pub struct PrefetchedBins {
+    results: HashMap<DiscInfo, Result<Option<Vec<u8>>, failure::Error>>,
+}
+
+impl PrefetchedBins {
+    /// Looks up every distinct disc in `source`, with at most `max_in_flight` lookups at a time.
+    /// A failed lookup does not stop the others, and is reported when the disc is requested from the result.
+    pub fn fetch<S: BinSource + Sync>(source: &S, discs: &[DiscInfo], max_in_flight: usize) -> Result<Self, failure::Error> {
+        let mut unique_discs = discs.to_vec();
+        unique_discs.sort_by_key(|d| d.ar_disc_id());
+        unique_discs.dedup();
+
+        // A dedicated pool bounds the number of requests, independently of the pool used for calculating CRCs.
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(max_in_flight.max(1)).build()?;
+
+        let results = pool.install(|| {
+            unique_discs.into_par_iter()
+                .map(|disc_info| (disc_info, source.get_ar_bin(&disc_info)))
+                .collect()
+        });
+
+        Ok(PrefetchedBins { results })
+    }
+}
+
+impl BinSource for PrefetchedBins {
+    fn get_ar_bin(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+        match self.results.get(disc_info) {
+            Some(&Ok(ref ar_bin_data)) => Ok(ar_bin_data.clone()),
+            Some(&Err(ref err)) => bail!("unable to fetch dBAR file: {}", err),
+            None => bail!("dBAR file for disc {} was not fetched", disc_info.ar_disc_id()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use failure;
+
+    use offset::DiscInfo;
+    use fetch::BinSource;
+
+    use super::PrefetchedBins;
+
+    /// Answers lookups slowly, while recording how many were requested and how many ran at once.
+    struct MockSource {
+        state: Mutex<(Vec<DiscInfo>, usize, usize)>,
+    }
+
+    impl BinSource for MockSource {
+        fn get_ar_bin(&self, disc_info: &DiscInfo) -> Result<Option<Vec<u8>>, failure::Error> {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.0.push(*disc_info);
+                state.1 += 1;
+                state.2 = state.2.max(state.1);
+            }
+
+            thread::sleep(Duration::from_millis(20));
+            self.state.lock().unwrap().1 -= 1;
+
+            match disc_info.num_tracks {
+                0 => bail!("server error"),
+                1 => Ok(None),
+                n => Ok(Some(vec![n])),
+            }
+        }
+    }
+
+    fn disc(num_tracks: u8, id_1: u32) -> DiscInfo {
+        DiscInfo { id_1, id_2: 0, cddb_id: 0, num_tracks }
+    }
+
+    #[test]
+    fn test_prefetched_bins() {
+        let discs = vec![disc(2, 1), disc(3, 2), disc(2, 1), disc(1, 3), disc(0, 4), disc(4, 5), disc(5, 6), disc(3, 2)];
+        let source = MockSource { state: Mutex::new((vec![], 0, 0)) };
+
+        let prefetched = PrefetchedBins::fetch(&source, &discs, 2).unwrap();
+
+        let (requested, _, max_in_flight) = source.state.into_inner().unwrap();
+
+        // Each distinct disc is requested once.
+        assert_eq!(6, requested.len());
+        assert!(max_in_flight <= 2);
+
+        assert_eq!(Some(vec![2]), prefetched.get_ar_bin(&disc(2, 1)).unwrap());
+        assert_eq!(Some(vec![3]), prefetched.get_ar_bin(&disc(3, 2)).unwrap());
+        assert_eq!(None, prefetched.get_ar_bin(&disc(1, 3)).unwrap());
+        assert!(prefetched.get_ar_bin(&disc(0, 4)).is_err());
+        assert!(prefetched.get_ar_bin(&disc(9, 9)).is_err());
+    }
+}
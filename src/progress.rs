@@ -0,0 +1,155 @@
+//! Reports the progress of long-running work, such as decoding and checksumming a whole disc.
+
+use std::cell::Cell;
+use std::io;
+use std::io::Write;
+
+use atty;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phase {
+    Identify,
+    Fetch,
+    Decode,
+    Checksum,
+    Match,
+}
+
+impl Phase {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Phase::Identify => "identifying",
+            Phase::Fetch => "fetching",
+            Phase::Decode => "decoding",
+            Phase::Checksum => "checksumming",
+            Phase::Match => "matching",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Number of the track being processed, starting at 1, if the phase works on a single track.
+    pub track: Option<usize>,
+    pub num_tracks: usize,
+    /// Size of the decoded audio processed so far.
+    pub bytes_done: u64,
+    /// Size of the decoded audio of all files to process, zero if not yet known.
+    pub bytes_total: u64,
+}
+
+impl Progress {
+    pub fn new(phase: Phase) -> Self {
+        Progress { phase, track: None, num_tracks: 0, bytes_done: 0, bytes_total: 0 }
+    }
+
+    /// Returns the completed fraction of the work, from 0.0 to 1.0.
+    pub fn fraction(&self) -> f64 {
+        if self.bytes_total == 0 { 0.0 } else { (self.bytes_done as f64 / self.bytes_total as f64).min(1.0) }
+    }
+}
+
+/// Receives progress updates.
+pub trait ProgressListener {
+    fn on_progress(&self, progress: &Progress);
+
+    /// Called once the work is done, e.g. to clear a progress bar before printing results.
+    fn finish(&self) {}
+}
+
+/// Ignores all progress updates.
+pub struct NoProgress;
+
+impl ProgressListener for NoProgress {
+    fn on_progress(&self, _progress: &Progress) {}
+}
+
+/// Redraws a single progress bar line on stderr.
+pub struct ProgressBar;
+
+impl ProgressListener for ProgressBar {
+    fn on_progress(&self, progress: &Progress) {
+        let _ = write!(io::stderr(), "\r\x1b[K{}", render_bar(progress, 30));
+        let _ = io::stderr().flush();
+    }
+
+    fn finish(&self) {
+        let _ = write!(io::stderr(), "\r\x1b[K");
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Prints a plain line on stderr whenever the phase or track changes, for logs that are not shown on a terminal.
+#[derive(Default)]
+pub struct ProgressLog {
+    last_step: Cell<Option<(Phase, Option<usize>)>>,
+}
+
+impl ProgressListener for ProgressLog {
+    fn on_progress(&self, progress: &Progress) {
+        let step = Some((progress.phase, progress.track));
+
+        if self.last_step.replace(step) == step {
+            return;
+        }
+
+        match progress.track {
+            Some(track) => eprintln!("{} track {}/{}", progress.phase.description(), track, progress.num_tracks),
+            None => eprintln!("{}", progress.phase.description()),
+        }
+    }
+}
+
+/// Returns a progress bar if output goes to a terminal, and plain log lines otherwise.
+pub fn for_terminal() -> Box<ProgressListener> {
+    if atty::is(atty::Stream::Stdout) && atty::is(atty::Stream::Stderr) {
+        Box::new(ProgressBar)
+    }
+    else {
+        Box::new(ProgressLog::default())
+    }
+}
+
+/// Formats a progress bar line, e.g. `[#####     ]  50%  decoding track 3/10`.
+pub fn render_bar(progress: &Progress, width: usize) -> String {
+    let filled = (progress.fraction() * width as f64).round() as usize;
+    let bar = format!("{}{}", "#".repeat(filled), " ".repeat(width - filled));
+    let percent = (progress.fraction() * 100.0).floor() as u32;
+
+    match progress.track {
+        Some(track) => format!("[{}] {:>3}%  {} track {}/{}", bar, percent, progress.phase.description(), track, progress.num_tracks),
+        None => format!("[{}] {:>3}%  {}", bar, percent, progress.phase.description()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_bar;
+    use super::Phase;
+    use super::Progress;
+
+    #[test]
+    fn test_render_bar() {
+        let inputs_and_expected = vec![
+            (Progress::new(Phase::Identify), "[          ]   0%  identifying"),
+            (
+                Progress { phase: Phase::Decode, track: Some(3), num_tracks: 10, bytes_done: 50, bytes_total: 100 },
+                "[#####     ]  50%  decoding track 3/10",
+            ),
+            (
+                Progress { phase: Phase::Checksum, track: Some(10), num_tracks: 10, bytes_done: 999, bytes_total: 1000 },
+                "[##########]  99%  checksumming track 10/10",
+            ),
+            (
+                Progress { phase: Phase::Match, track: None, num_tracks: 10, bytes_done: 1000, bytes_total: 1000 },
+                "[##########] 100%  matching",
+            ),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = render_bar(&input, 10);
+            assert_eq!(expected, produced);
+        }
+    }
+}
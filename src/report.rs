@@ -0,0 +1,343 @@
+//! Writes verification reports for archiving next to an album, in the spirit of the logs written by rippers.
+//! Each report ends with a checksum over its body, so that later bit rot can be detected.
+//! Anyone editing a report can recompute a plain checksum, so to also detect tampering, reports are signed
+//! with a keyed HMAC instead if a report key is configured.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use failure;
+use md5;
+
+use toc::Toc;
+use validate::AccuracyRequirement;
+use validate::DiagnosticLevel;
+use validate::ValidationReport;
+
+/// Default name of a report file within an album directory.
+pub const REPORT_FILE_NAME: &str = "ar-flac-rs.log";
+
+const CHECKSUM_PREFIX: &str = "==== Report checksum: ";
+const SIGNATURE_PREFIX: &str = "==== Report signature: ";
+const CHECKSUM_SUFFIX: &str = " ====";
+
+/// Block size of MD5, which HMAC pads keys to.
+const HMAC_BLOCK_LEN: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReportCheck {
+    Valid,
+    /// The report matches its checksum, but is not signed although a key is configured, so it may have been edited.
+    Unsigned,
+    /// The report body no longer matches its checksum or signature.
+    Modified,
+}
+
+fn body_checksum(body: &str) -> String {
+    format!("{:x}", md5::compute(body.as_bytes()))
+}
+
+/// Calculates the HMAC-MD5 of a report body, as described in RFC 2104.
+fn body_signature(body: &str, key: &[u8]) -> String {
+    let mut key_block = [0u8; HMAC_BLOCK_LEN];
+
+    if key.len() > HMAC_BLOCK_LEN {
+        key_block[..16].copy_from_slice(&md5::compute(key).0);
+    }
+    else {
+        key_block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = md5::Context::new();
+    inner.consume(key_block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.consume(body.as_bytes());
+
+    let mut outer = md5::Context::new();
+    outer.consume(key_block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.consume(&inner.compute().0);
+
+    format!("{:x}", outer.compute())
+}
+
+/// Formats a report for a validated disc, ending with a signature line if `key` is given, and a checksum line otherwise.
+pub fn write_report(toc: &Toc, report: &ValidationReport, key: Option<&str>) -> String {
+    let mut lines = vec![];
+
+    lines.push(format!("ar-flac-rs {} verification report", env!("CARGO_PKG_VERSION")));
+    lines.push(String::new());
+
+    if let Some(album_dir) = report.tracks.first().and_then(|t| t.path.parent()) {
+        lines.push(format!("Album: {}", album_dir.display()));
+        lines.push(String::new());
+    }
+
+    lines.push("TOC".to_string());
+    lines.push("  Track |  Start sector |  End sector".to_string());
+
+    let end_offsets = toc.track_offsets.iter().skip(1).chain(Some(&toc.leadout));
+
+    for (track_index, (start, end)) in toc.track_offsets.iter().zip(end_offsets).enumerate() {
+        lines.push(format!("  {:>5} | {:>13} | {:>11}", track_index + 1, start, end - 1));
+    }
+
+    lines.push(format!("  Lead-out sector: {}", toc.leadout));
+
+    if toc.pregap_length() > 0 {
+        lines.push(format!("  Hidden track one audio: {} sectors", toc.pregap_length()));
+    }
+
+    lines.push(String::new());
+    lines.push(format!("Disc ID: {}", report.disc_info.ar_disc_id()));
+    lines.push(format!("Tracks on disc: {}", report.disc_info.num_tracks));
+    lines.push(format!("Pressings in database: {}", report.num_pressings));
+
+    if let Some(matched_pressing) = report.matched_pressing {
+        lines.push(format!("Matched pressing: {}", matched_pressing));
+    }
+    else if report.mixed_pressings {
+        lines.push("Matched pressing: mixed".to_string());
+    }
+
+    for track in &report.tracks {
+        let file_name = track.path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default();
+        let copy_checksums = &track.copy_checksums;
+
+        lines.push(String::new());
+        lines.push(format!("Track {}", track.track_num));
+        lines.push(format!("  File: {}", file_name));
+        lines.push(format!("  AR v1: {:0>8X}", track.crcs.v1));
+        lines.push(format!("  AR v2: {:0>8X}", track.crcs.v2));
+
+        if let Some(crc32) = copy_checksums.crc32 {
+            lines.push(format!("  Copy CRC: {:0>8X}", crc32));
+        }
+        if let Some(crc32_skip_zero) = copy_checksums.crc32_skip_zero {
+            lines.push(format!("  Copy CRC w/o null samples: {:0>8X}", crc32_skip_zero));
+        }
+        if let Some(peak_percent) = copy_checksums.peak_percent() {
+            lines.push(format!("  Peak level: {:.1} %", peak_percent));
+        }
+        if track.offset != 0 {
+            lines.push(format!("  Offset: {}", track.offset));
+        }
+
+        lines.push(format!("  Confidence: {}", track.total_confidence));
+
+        if let Some(pressing) = track.matched_pressing {
+            lines.push(format!("  Pressing: {}", pressing));
+        }
+
+        lines.push(format!("  Status: {}", track.status.description()));
+    }
+
+    if !report.diagnostics.is_empty() {
+        lines.push(String::new());
+
+        for diagnostic in &report.diagnostics {
+            let level = match diagnostic.level {
+                DiagnosticLevel::Info => "info",
+                DiagnosticLevel::Warning => "warning",
+            };
+
+            lines.push(format!("{}: {}", level, diagnostic.message));
+        }
+    }
+
+    let num_accurate = report.tracks.iter().filter(|t| t.status.is_accurate()).count();
+
+    lines.push(String::new());
+    lines.push(format!("{} of {} tracks accurate", num_accurate, report.tracks.len()));
+    lines.push(if report.meets(AccuracyRequirement::HighConfidenceV2) {
+        "All tracks accurately ripped".to_string()
+    }
+    else if report.is_accurate() {
+        "All tracks accurately ripped, but some only with low confidence or via v1 CRCs".to_string()
+    }
+    else {
+        "Not all tracks could be verified as accurate".to_string()
+    });
+    lines.push(String::new());
+
+    let body = lines.join("\n") + "\n";
+
+    match key {
+        Some(key) => format!("{}{}{}{}\n", body, SIGNATURE_PREFIX, body_signature(&body, key.as_bytes()), CHECKSUM_SUFFIX),
+        None => format!("{}{}{}{}\n", body, CHECKSUM_PREFIX, body_checksum(&body), CHECKSUM_SUFFIX),
+    }
+}
+
+/// Writes a report to a file, replacing any existing report.
+pub fn save_report<P: AsRef<Path>>(path: P, toc: &Toc, report: &ValidationReport, key: Option<&str>) -> Result<(), failure::Error> {
+    File::create(path)?.write_all(write_report(toc, report, key).as_bytes())?;
+
+    Ok(())
+}
+
+/// Checks that a report still matches the checksum or signature at its end.
+/// With a `key`, reports with only a checksum are reported as unsigned, since they could have been edited on purpose.
+/// Fails if the report has no checksum line at all, e.g. because it was truncated, or if it is signed but no key is given.
+pub fn check_report(text: &str, key: Option<&str>) -> Result<ReportCheck, failure::Error> {
+    // Whichever line comes last is the one that was written with the report.
+    let (checksum_pos, prefix) = match (text.rfind(SIGNATURE_PREFIX), text.rfind(CHECKSUM_PREFIX)) {
+        (Some(signature_pos), Some(checksum_pos)) if checksum_pos > signature_pos => (checksum_pos, CHECKSUM_PREFIX),
+        (Some(signature_pos), _) => (signature_pos, SIGNATURE_PREFIX),
+        (None, Some(checksum_pos)) => (checksum_pos, CHECKSUM_PREFIX),
+        (None, None) => bail!("report has no checksum"),
+    };
+
+    let (body, checksum_line) = text.split_at(checksum_pos);
+    let checksum_line = checksum_line.trim_right();
+
+    if !checksum_line.ends_with(CHECKSUM_SUFFIX) {
+        bail!("report has a malformed checksum line");
+    }
+
+    let expected = &checksum_line[prefix.len()..checksum_line.len() - CHECKSUM_SUFFIX.len()];
+
+    let produced = match (prefix == SIGNATURE_PREFIX, key) {
+        (true, Some(key)) => body_signature(body, key.as_bytes()),
+        (true, None) => bail!("report is signed, but no report key is configured"),
+        (false, _) => body_checksum(body),
+    };
+
+    if produced != expected {
+        Ok(ReportCheck::Modified)
+    }
+    else if prefix == CHECKSUM_PREFIX && key.is_some() {
+        Ok(ReportCheck::Unsigned)
+    }
+    else {
+        Ok(ReportCheck::Valid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use offset::SAMPLE_DISC_INFO;
+    use fetch::unpack_ar_bin;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::CopyChecksums;
+    use config::ThresholdConfig;
+    use toc::Toc;
+    use validate::build_report;
+    use validate::LocalTrack;
+
+    use test_util::load_sample_bin;
+
+    use super::body_signature;
+    use super::check_report;
+    use super::write_report;
+    use super::ReportCheck;
+
+    fn sample_report(key: Option<&str>) -> String {
+        let bin_results = unpack_ar_bin(&load_sample_bin()).unwrap();
+
+        let local_tracks = bin_results[1].1.iter().enumerate().map(|(i, t)| LocalTrack {
+            path: PathBuf::from("/music/album").join(format!("{:02}.flac", i + 1)),
+            crcs: CRCs { v1: 0, v2: t.crc },
+            copy_checksums: CopyChecksums { crc32: Some(0xDEADBEEF), crc32_skip_zero: None, peak: Some(32768) },
+            md5_check: Md5Check::Match,
+        }).collect();
+
+        let report = build_report(SAMPLE_DISC_INFO, local_tracks, Some(&bin_results), &ThresholdConfig::default());
+        let toc = Toc::from_frame_lengths(vec![16172, 15115, 23190, 17425, 17695, 23690, 16645, 19350, 30460, 33621]);
+
+        write_report(&toc, &report, key)
+    }
+
+    #[test]
+    fn test_write_report() {
+        let produced = sample_report(None);
+
+        let expected_lines = vec![
+            "Album: /music/album",
+            "      1 |             0 |       16171",
+            "  Lead-out sector: 213363",
+            "Disc ID: 010-0012baaf-0094edfd-8b0b500a",
+            "Tracks on disc: 10",
+            "Matched pressing: 1",
+            "Track 1",
+            "  File: 01.flac",
+            "  AR v2: DE813995",
+            "  Copy CRC: DEADBEEF",
+            "  Peak level: 100.0 %",
+            "  Confidence: 119",
+            "  Status: accurate",
+            "Track 10",
+            "  File: 10.flac",
+            "  AR v2: 6B47A018",
+            "  Confidence: 120",
+            "10 of 10 tracks accurate",
+        ];
+
+        for expected_line in expected_lines {
+            assert!(produced.lines().any(|line| line == expected_line), "missing line: {}", expected_line);
+        }
+
+        assert!(produced.starts_with("ar-flac-rs "));
+        assert!(produced.lines().last().unwrap().starts_with("==== Report checksum: "));
+    }
+
+    #[test]
+    fn test_body_signature() {
+        // Test cases 1 and 6 of RFC 2202.
+        let inputs_and_expected = vec![
+            (("Hi There", vec![0x0b; 16]), "9294727a3638bb1c13f48ef8158bfc9d"),
+            (("Test Using Larger Than Block-Size Key - Hash Key First", vec![0xaa; 80]), "6b1ab7fe4bd7bf8f0b62e6ce61b9d0cd"),
+        ];
+
+        for ((body, key), expected) in inputs_and_expected {
+            let produced = body_signature(body, &key);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_check_report() {
+        let report = sample_report(None);
+
+        let inputs_and_expected = vec![
+            (report.clone(), ReportCheck::Valid),
+            // Line endings converted by a text editor or file transfer.
+            (report.replace("\n", "\r\n"), ReportCheck::Modified),
+            (report.replace("AR v2: DE813995", "AR v2: DE813996"), ReportCheck::Modified),
+            (report.replacen("accurate", "accuratE", 1), ReportCheck::Modified),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = check_report(&input, None).unwrap();
+            assert_eq!(expected, produced);
+        }
+
+        let truncated = &report[..report.len() / 2];
+        assert!(check_report(truncated, None).is_err());
+    }
+
+    #[test]
+    fn test_check_signed_report() {
+        let report = sample_report(Some("secret"));
+        assert!(report.lines().last().unwrap().starts_with("==== Report signature: "));
+
+        let edited = report.replace("AR v2: DE813995", "AR v2: DE813996");
+
+        let inputs_and_expected = vec![
+            ((report.clone(), Some("secret")), ReportCheck::Valid),
+            ((report.clone(), Some("other")), ReportCheck::Modified),
+            ((edited, Some("secret")), ReportCheck::Modified),
+            // A report with only a checksum could have been edited and re-checksummed.
+            ((sample_report(None), Some("secret")), ReportCheck::Unsigned),
+            ((sample_report(None), None), ReportCheck::Valid),
+        ];
+
+        for ((input, key), expected) in inputs_and_expected {
+            let produced = check_report(&input, key).unwrap();
+            assert_eq!(expected, produced);
+        }
+
+        assert!(check_report(&report, None).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Re-verifies albums against the CRCs recorded when they were last verified, without querying the database.
+//! This detects audio that changed since verification, e.g. from bit rot on long-term storage.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure;
+use metaflac::Tag;
+
+use crc::CRCs;
+use checksum::ChecksumSelection;
+use config::Config;
+use decode::Md5Check;
+use progress::ProgressListener;
+use report::check_report;
+use report::ReportCheck;
+use report::REPORT_FILE_NAME;
+use tag::CRC_KEY;
+use tag::V2_CRC_KEY;
+use validate::calc_partial_track_crcs;
+use validate::identify_album;
+use validate::LocalTrack;
+
+/// AccurateRip CRCs of a track, as recorded when it was verified.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoredTrack {
+    pub path: PathBuf,
+    /// Number of the track on its disc, starting at 1.
+    pub track_num: u8,
+    pub crcs: CRCs,
+}
+
+/// Recorded CRCs of the verified tracks of a disc, which need not be all of its tracks.
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoredDisc {
+    pub num_tracks: u8,
+    pub tracks: Vec<StoredTrack>,
+}
+
+/// Where the CRCs recorded at verification time are read from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Reference {
+    /// The report written into the album directory.
+    Report,
+    /// The tags written into each file by tagging mode.
+    Tags,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IntegrityStatus {
+    /// The audio still has the recorded CRCs.
+    Unchanged,
+    /// The audio no longer has the recorded CRCs.
+    Changed,
+    /// The audio does not match the MD5 signature in the file, regardless of the recorded CRCs.
+    Corrupted,
+    /// No CRCs were recorded for the file.
+    NotRecorded,
+    /// CRCs were recorded for a file that no longer exists.
+    Missing,
+}
+
+impl IntegrityStatus {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            IntegrityStatus::Unchanged => "unchanged",
+            IntegrityStatus::Changed => "CHANGED",
+            IntegrityStatus::Corrupted => "CORRUPTED",
+            IntegrityStatus::NotRecorded => "not recorded",
+            IntegrityStatus::Missing => "MISSING",
+        }
+    }
+
+    /// Returns true if the audio of the track changed or is gone, as opposed to being unchanged or never recorded.
+    pub fn is_changed(&self) -> bool {
+        match *self {
+            IntegrityStatus::Changed | IntegrityStatus::Corrupted | IntegrityStatus::Missing => true,
+            IntegrityStatus::Unchanged | IntegrityStatus::NotRecorded => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct IntegrityResult {
+    pub path: PathBuf,
+    pub stored_crcs: Option<CRCs>,
+    pub computed_crcs: Option<CRCs>,
+    pub status: IntegrityStatus,
+}
+
+fn parse_crc(text: &str) -> Result<u32, failure::Error> {
+    u32::from_str_radix(text.trim(), 16).map_err(|_| format_err!("invalid CRC: {}", text))
+}
+
+/// Reads the CRCs recorded in a report written by `report::write_report`.
+/// File names in the report are resolved relative to `album_dir`.
+/// Fails if the report no longer matches its checksum or signature, since its CRCs cannot be trusted then.
+pub fn stored_disc_from_report<P: AsRef<Path>>(text: &str, album_dir: P, key: Option<&str>) -> Result<StoredDisc, failure::Error> {
+    match check_report(text, key)? {
+        ReportCheck::Valid => {},
+        ReportCheck::Unsigned => bail!("report is not signed, although a report key is configured"),
+        ReportCheck::Modified => bail!("report does not match its checksum, so it was modified or corrupted"),
+    }
+
+    let parse_num = |text: &str| text.trim().parse::<u8>().map_err(|_| format_err!("invalid track number: {}", text));
+
+    let mut num_tracks: Option<u8> = None;
+    let mut stored_tracks = vec![];
+    let mut track_num: Option<u8> = None;
+    let mut file_name: Option<&str> = None;
+    let mut v1: Option<u32> = None;
+
+    for raw_line in text.lines() {
+        let line = raw_line.trim();
+
+        if line.starts_with("Tracks on disc: ") {
+            num_tracks = Some(parse_num(&line["Tracks on disc: ".len()..])?);
+        }
+        // Only track headings start unindented, unlike the header of the TOC table.
+        else if raw_line.starts_with("Track ") {
+            track_num = Some(parse_num(&line["Track ".len()..])?);
+            file_name = None;
+            v1 = None;
+        }
+        else if line.starts_with("File: ") {
+            file_name = Some(&line["File: ".len()..]);
+        }
+        else if line.starts_with("AR v1: ") {
+            v1 = Some(parse_crc(&line["AR v1: ".len()..])?);
+        }
+        else if line.starts_with("AR v2: ") {
+            let v2 = parse_crc(&line["AR v2: ".len()..])?;
+
+            match (track_num.take(), file_name.take(), v1.take()) {
+                (Some(track_num), Some(file_name), Some(v1)) => stored_tracks.push(StoredTrack {
+                    path: album_dir.as_ref().join(file_name),
+                    track_num,
+                    crcs: CRCs { v1, v2 },
+                }),
+                _ => bail!("report has a track without a track number, file name or v1 CRC"),
+            }
+        }
+    }
+
+    if stored_tracks.is_empty() {
+        bail!("report contains no tracks");
+    }
+
+    // Reports written before the track count was recorded always covered the whole disc.
+    let num_tracks = num_tracks.unwrap_or(stored_tracks.len() as u8);
+
+    if let Some(stored_track) = stored_tracks.iter().find(|s| s.track_num == 0 || s.track_num > num_tracks) {
+        bail!("report has track {}, but the disc only has {} tracks", stored_track.track_num, num_tracks);
+    }
+
+    Ok(StoredDisc { num_tracks, tracks: stored_tracks })
+}
+
+/// Reads the CRCs recorded in the Vorbis comments written by tagging mode.
+/// Tagging mode only handles complete discs, so each file is numbered by its position among `flac_files`.
+/// Files without recorded CRCs are skipped, but fails if none of the files has any.
+pub fn stored_disc_from_tags(flac_files: &[PathBuf]) -> Result<StoredDisc, failure::Error> {
+    let mut stored_tracks = vec![];
+
+    for (track_index, flac_file) in flac_files.iter().enumerate() {
+        let flac_tag = Tag::read_from_path(flac_file)?;
+        let get_value = |key| flac_tag.get_vorbis(key).and_then(|values| values.first().cloned());
+
+        if let (Some(v1), Some(v2)) = (get_value(CRC_KEY), get_value(V2_CRC_KEY)) {
+            stored_tracks.push(StoredTrack {
+                path: flac_file.clone(),
+                track_num: track_index as u8 + 1,
+                crcs: CRCs { v1: parse_crc(&v1)?, v2: parse_crc(&v2)? },
+            });
+        }
+    }
+
+    if stored_tracks.is_empty() {
+        bail!("no file has AccurateRip CRCs in its tags");
+    }
+
+    Ok(StoredDisc { num_tracks: flac_files.len() as u8, tracks: stored_tracks })
+}
+
+/// Compares freshly calculated CRCs of every track of an album against the recorded ones.
+/// Tracks are matched by file name, so the result covers both recorded and current files.
+pub fn compare_tracks(stored_tracks: &[StoredTrack], local_tracks: &[LocalTrack]) -> Vec<IntegrityResult> {
+    let same_file = |a: &Path, b: &Path| a.file_name() == b.file_name();
+    let mut results = vec![];
+
+    for local_track in local_tracks {
+        let stored_crcs = stored_tracks.iter().find(|s| same_file(&s.path, &local_track.path)).map(|s| s.crcs);
+
+        let status = match stored_crcs {
+            _ if local_track.md5_check == Md5Check::Mismatch => IntegrityStatus::Corrupted,
+            Some(stored_crcs) if stored_crcs == local_track.crcs => IntegrityStatus::Unchanged,
+            Some(_) => IntegrityStatus::Changed,
+            None => IntegrityStatus::NotRecorded,
+        };
+
+        results.push(IntegrityResult {
+            path: local_track.path.clone(),
+            stored_crcs,
+            computed_crcs: Some(local_track.crcs),
+            status,
+        });
+    }
+
+    for stored_track in stored_tracks {
+        if !local_tracks.iter().any(|l| same_file(&l.path, &stored_track.path)) {
+            results.push(IntegrityResult {
+                path: stored_track.path.clone(),
+                stored_crcs: Some(stored_track.crcs),
+                computed_crcs: None,
+                status: IntegrityStatus::Missing,
+            });
+        }
+    }
+
+    results
+}
+
+/// Recalculates the CRCs of every track of an album and compares them against the recorded ones.
+/// Each recorded track is checked as the track of the disc it was verified as, so reports of some tracks of a disc work too.
+pub fn reverify<P: AsRef<Path>>(
+    album_dir: P,
+    reference: Reference,
+    config: &Config,
+    progress: &ProgressListener,
+) -> Result<Vec<IntegrityResult>, failure::Error> {
+    let album_dir = album_dir.as_ref();
+    let album = identify_album(album_dir)?;
+
+    let stored_disc = match reference {
+        Reference::Report => {
+            let report_path = album_dir.join(REPORT_FILE_NAME);
+            let bytes = fs::read(&report_path).map_err(|err| format_err!("unable to read {}: {}", report_path.display(), err))?;
+            stored_disc_from_report(&String::from_utf8_lossy(&bytes), album_dir, config.report.key())?
+        },
+        Reference::Tags => stored_disc_from_tags(&album.track_files)?,
+    };
+
+    // Whether a track is the first or last one of the disc changes its CRCs, so unrecorded files cannot be checked.
+    let is_recorded = |path: &Path| stored_disc.tracks.iter().any(|s| s.path.file_name() == path.file_name());
+    let tracks = stored_disc.tracks.iter()
+        .filter_map(|s| album.track_files.iter().find(|f| f.file_name() == s.path.file_name()).map(|f| (s.track_num, f.clone())))
+        .collect::<Vec<_>>();
+
+    let local_tracks = calc_partial_track_crcs(&config.decoder, &tracks, stored_disc.num_tracks, ChecksumSelection::none(), progress)?;
+    let mut results = compare_tracks(&stored_disc.tracks, &local_tracks);
+
+    for flac_file in album.track_files.iter().filter(|f| !is_recorded(f)) {
+        results.push(IntegrityResult {
+            path: flac_file.clone(),
+            stored_crcs: None,
+            computed_crcs: None,
+            status: IntegrityStatus::NotRecorded,
+        });
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use offset::DiscInfo;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::CopyChecksums;
+    use config::ThresholdConfig;
+    use report::write_report;
+    use toc::Toc;
+    use validate::build_partial_report;
+    use validate::build_report;
+    use validate::LocalTrack;
+
+    use super::compare_tracks;
+    use super::stored_disc_from_report;
+    use super::IntegrityStatus;
+    use super::StoredDisc;
+    use super::StoredTrack;
+
+    fn local_track(path: &str, v1: u32, v2: u32, md5_check: Md5Check) -> LocalTrack {
+        LocalTrack { path: PathBuf::from(path), crcs: CRCs { v1, v2 }, copy_checksums: CopyChecksums::default(), md5_check }
+    }
+
+    fn stored_track(path: &str, track_num: u8, v1: u32, v2: u32) -> StoredTrack {
+        StoredTrack { path: PathBuf::from(path), track_num, crcs: CRCs { v1, v2 } }
+    }
+
+    #[test]
+    fn test_stored_disc_from_report() {
+        let disc_info = DiscInfo { id_1: 1, id_2: 2, cddb_id: 3, num_tracks: 2 };
+        let local_tracks = vec![
+            local_track("/old/place/01 - Intro.flac", 0x11111111, 0xAAAAAAAA, Md5Check::Match),
+            local_track("/old/place/02 - Outro.flac", 0x22222222, 0xBBBBBBBB, Md5Check::Match),
+        ];
+
+        let report = build_report(disc_info, local_tracks, None, &ThresholdConfig::default());
+        let text = write_report(&Toc::from_frame_lengths(vec![100, 200]), &report, None);
+
+        let expected = StoredDisc {
+            num_tracks: 2,
+            tracks: vec![
+                stored_track("/new/place/01 - Intro.flac", 1, 0x11111111, 0xAAAAAAAA),
+                stored_track("/new/place/02 - Outro.flac", 2, 0x22222222, 0xBBBBBBBB),
+            ],
+        };
+
+        let produced = stored_disc_from_report(&text, "/new/place", None).unwrap();
+        assert_eq!(expected, produced);
+
+        // Reports that were modified are rejected.
+        let modified = text.replace("AR v2: AAAAAAAA", "AR v2: AAAAAAAB");
+        assert!(stored_disc_from_report(&modified, "/new/place", None).is_err());
+
+        // Reports that are not signed are rejected once a key is configured.
+        assert!(stored_disc_from_report(&text, "/new/place", Some("secret")).is_err());
+
+        let signed = write_report(&Toc::from_frame_lengths(vec![100, 200]), &report, Some("secret"));
+        assert_eq!(expected, stored_disc_from_report(&signed, "/new/place", Some("secret")).unwrap());
+    }
+
+    #[test]
+    fn test_stored_disc_from_partial_report() {
+        let toc = Toc::from_frame_lengths(vec![100, 200, 300, 400, 500]);
+        let local_tracks = vec![
+            (2, local_track("/album/02.flac", 0x22222222, 0xBBBBBBBB, Md5Check::Match)),
+            (5, local_track("/album/05.flac", 0x55555555, 0xEEEEEEEE, Md5Check::Match)),
+        ];
+
+        let report = build_partial_report(toc.disc_info().unwrap(), local_tracks, None, &ThresholdConfig::default());
+        let text = write_report(&toc, &report, None);
+
+        // The tracks keep their place on the disc, so that they are checked as the tracks they were verified as.
+        let expected = StoredDisc {
+            num_tracks: 5,
+            tracks: vec![
+                stored_track("/album/02.flac", 2, 0x22222222, 0xBBBBBBBB),
+                stored_track("/album/05.flac", 5, 0x55555555, 0xEEEEEEEE),
+            ],
+        };
+
+        let produced = stored_disc_from_report(&text, "/album", None).unwrap();
+        assert_eq!(expected, produced);
+    }
+
+    #[test]
+    fn test_compare_tracks() {
+        let stored_tracks = vec![
+            stored_track("/album/01.flac", 1, 1, 2),
+            stored_track("/album/02.flac", 2, 3, 4),
+            stored_track("/album/03.flac", 3, 5, 6),
+            stored_track("/album/04.flac", 4, 7, 8),
+        ];
+
+        let local_tracks = vec![
+            local_track("/album/01.flac", 1, 2, Md5Check::Match),
+            local_track("/album/02.flac", 3, 5, Md5Check::Unavailable),
+            local_track("/album/03.flac", 5, 6, Md5Check::Mismatch),
+            local_track("/album/05.flac", 9, 10, Md5Check::Match),
+        ];
+
+        let expected = vec![
+            ("/album/01.flac", IntegrityStatus::Unchanged),
+            ("/album/02.flac", IntegrityStatus::Changed),
+            ("/album/03.flac", IntegrityStatus::Corrupted),
+            ("/album/05.flac", IntegrityStatus::NotRecorded),
+            ("/album/04.flac", IntegrityStatus::Missing),
+        ];
+
+        let produced = compare_tracks(&stored_tracks, &local_tracks);
+        let produced = produced.iter().map(|r| (r.path.to_str().unwrap(), r.status)).collect::<Vec<_>>();
+
+        assert_eq!(expected, produced);
+    }
+}
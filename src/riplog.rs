@@ -0,0 +1,476 @@
+//! Parses EAC, XLD and whipper rip logs, and compares them against local FLAC files.
+
+use std::path::Path;
+
+use failure;
+
+use offset::FrameLength;
+use crc::CRC;
+use validate::LocalTrack;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RipLogKind {
+    Eac,
+    Xld,
+    Whipper,
+}
+
+/// A single row of the TOC table in a rip log.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TocEntry {
+    pub track_num: u8,
+    pub start_sector: u64,
+    pub end_sector: u64,
+}
+
+impl TocEntry {
+    pub fn frame_length(&self) -> FrameLength {
+        self.end_sector - self.start_sector + 1
+    }
+}
+
+/// Per-track checksums recorded by the ripper.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LogTrack {
+    pub track_num: u8,
+    pub test_crc: Option<CRC>,
+    pub copy_crc: Option<CRC>,
+    pub ar_v1: Option<CRC>,
+    pub ar_v2: Option<CRC>,
+}
+
+impl LogTrack {
+    fn new(track_num: u8) -> Self {
+        LogTrack { track_num, test_crc: None, copy_crc: None, ar_v1: None, ar_v2: None }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RipLog {
+    pub kind: RipLogKind,
+    pub read_offset: Option<i32>,
+    pub toc: Vec<TocEntry>,
+    pub tracks: Vec<LogTrack>,
+}
+
+/// Reads a text file from disk, handling the UTF-16 encoding EAC uses for its logs.
+pub fn read_log_text<P: AsRef<Path>>(log_path: P) -> Result<String, failure::Error> {
+    let bytes = ::std::fs::read(log_path)?;
+
+    if bytes.starts_with(&[0xFF, 0xFE]) {
+        let units: Vec<u16> = bytes[2..].chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| c[0] as u16 | (c[1] as u16) << 8)
+            .collect();
+
+        Ok(String::from_utf16(&units)?)
+    }
+    else {
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+pub fn read_rip_log<P: AsRef<Path>>(log_path: P) -> Result<RipLog, failure::Error> {
+    parse_rip_log(&read_log_text(log_path)?)
+}
+
+pub fn parse_rip_log(text: &str) -> Result<RipLog, failure::Error> {
+    let text = text.trim_left_matches('\u{FEFF}');
+    let first_line = text.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+
+    let kind = if first_line.starts_with("Exact Audio Copy") || first_line.starts_with("EAC extraction logfile") {
+        RipLogKind::Eac
+    }
+    else if first_line.starts_with("X Lossless Decoder") {
+        RipLogKind::Xld
+    }
+    else if first_line.starts_with("Log created by: whipper") {
+        RipLogKind::Whipper
+    }
+    else {
+        bail!("unrecognized rip log format");
+    };
+
+    let read_offset = parse_read_offset(text);
+
+    let (toc, tracks) = match kind {
+        RipLogKind::Eac | RipLogKind::Xld => (parse_toc_table(text), parse_eac_xld_tracks(text)),
+        RipLogKind::Whipper => parse_whipper(text),
+    };
+
+    if toc.is_empty() {
+        bail!("no TOC found in rip log");
+    }
+
+    Ok(RipLog { kind, read_offset, toc, tracks })
+}
+
+fn value_after_colon(line: &str) -> Option<&str> {
+    line.find(':').map(|i| line[i + 1..].trim())
+}
+
+fn parse_crc(s: &str) -> Option<CRC> {
+    CRC::from_str_radix(s.trim(), 16).ok()
+}
+
+fn parse_read_offset(text: &str) -> Option<i32> {
+    text.lines()
+        .find(|l| l.trim_left().starts_with("Read offset correction"))
+        .and_then(value_after_colon)
+        .and_then(|v| v.trim_left_matches('+').parse().ok())
+}
+
+/// Parses the `Track | Start | Length | Start sector | End sector` table shared by EAC and XLD logs.
+pub fn parse_toc_table(text: &str) -> Vec<TocEntry> {
+    let mut toc = vec![];
+
+    for line in text.lines() {
+        let columns: Vec<&str> = line.split('|').map(str::trim).collect();
+
+        if columns.len() != 5 {
+            continue;
+        }
+
+        let parsed = (columns[0].parse(), columns[3].parse(), columns[4].parse());
+
+        if let (Ok(track_num), Ok(start_sector), Ok(end_sector)) = parsed {
+            toc.push(TocEntry { track_num, start_sector, end_sector });
+        }
+    }
+
+    toc
+}
+
+fn parse_eac_xld_tracks(text: &str) -> Vec<LogTrack> {
+    let mut tracks: Vec<LogTrack> = vec![];
+
+    for line in text.lines() {
+        // Track headers are unindented, e.g. `Track  1` (EAC) or `Track 01` (XLD).
+        if line.starts_with("Track") {
+            if let Ok(track_num) = line["Track".len()..].trim().parse() {
+                tracks.push(LogTrack::new(track_num));
+            }
+            continue;
+        }
+
+        let track = match tracks.last_mut() {
+            Some(track) => track,
+            None => continue,
+        };
+
+        let line = line.trim();
+
+        // EAC.
+        if line.starts_with("Test CRC ") {
+            track.test_crc = parse_crc(&line["Test CRC ".len()..]);
+        }
+        else if line.starts_with("Copy CRC ") {
+            track.copy_crc = parse_crc(&line["Copy CRC ".len()..]);
+        }
+        else if line.starts_with("Accurately ripped") || line.starts_with("Cannot be verified as accurate") {
+            // The first bracketed value is the local CRC. Logs from before AR v2 have no version suffix.
+            let crc = line.find('[')
+                .and_then(|i| line[i + 1..].find(']').map(|j| &line[i + 1..i + 1 + j]))
+                .and_then(parse_crc);
+
+            if line.ends_with("(AR v2)") {
+                track.ar_v2 = crc;
+            }
+            else {
+                track.ar_v1 = crc;
+            }
+        }
+        // XLD.
+        else if line.starts_with("CRC32 hash (test run)") {
+            track.test_crc = value_after_colon(line).and_then(parse_crc);
+        }
+        else if line.starts_with("CRC32 hash") && !line.starts_with("CRC32 hash (") {
+            track.copy_crc = value_after_colon(line).and_then(parse_crc);
+        }
+        else if line.starts_with("AccurateRip v1 signature") {
+            track.ar_v1 = value_after_colon(line).and_then(parse_crc);
+        }
+        else if line.starts_with("AccurateRip v2 signature") {
+            track.ar_v2 = value_after_colon(line).and_then(parse_crc);
+        }
+    }
+
+    tracks
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WhipperSection {
+    Other,
+    Toc,
+    Tracks,
+}
+
+/// Whipper logs are YAML, but only a small, fixed subset of it needs to be understood.
+fn parse_whipper(text: &str) -> (Vec<TocEntry>, Vec<LogTrack>) {
+    let mut toc: Vec<TocEntry> = vec![];
+    let mut tracks: Vec<LogTrack> = vec![];
+
+    let mut section = WhipperSection::Other;
+    let mut ar_version = 0;
+
+    for line in text.lines() {
+        let indent = line.len() - line.trim_left().len();
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if indent == 0 {
+            section = match line {
+                "TOC:" => WhipperSection::Toc,
+                "Tracks:" => WhipperSection::Tracks,
+                _ => WhipperSection::Other,
+            };
+            continue;
+        }
+
+        let key = line.split(':').next().unwrap_or("");
+        let value = value_after_colon(line).unwrap_or("");
+
+        match section {
+            WhipperSection::Other => {},
+            WhipperSection::Toc => {
+                if indent == 2 {
+                    if let Ok(track_num) = key.parse() {
+                        toc.push(TocEntry { track_num, start_sector: 0, end_sector: 0 });
+                    }
+                }
+                else if let Some(entry) = toc.last_mut() {
+                    match key {
+                        "Start sector" => entry.start_sector = value.parse().unwrap_or(0),
+                        "End sector" => entry.end_sector = value.parse().unwrap_or(0),
+                        _ => {},
+                    }
+                }
+            },
+            WhipperSection::Tracks => {
+                if indent == 2 {
+                    if let Ok(track_num) = key.parse() {
+                        tracks.push(LogTrack::new(track_num));
+                        ar_version = 0;
+                    }
+                }
+                else if let Some(track) = tracks.last_mut() {
+                    match key {
+                        "Test CRC" => track.test_crc = parse_crc(value),
+                        "Copy CRC" => track.copy_crc = parse_crc(value),
+                        "AccurateRip v1" => ar_version = 1,
+                        "AccurateRip v2" => ar_version = 2,
+                        "Local CRC" if ar_version == 1 => track.ar_v1 = parse_crc(value),
+                        "Local CRC" if ar_version == 2 => track.ar_v2 = parse_crc(value),
+                        _ => {},
+                    }
+                }
+            },
+        }
+    }
+
+    (toc, tracks)
+}
+
+/// Outcome of comparing one track of a rip log against the local file.
+/// A `None` field means the log did not record the corresponding value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrackComparison {
+    pub track_num: u8,
+    pub length_matches: Option<bool>,
+    pub ar_v1_matches: Option<bool>,
+    pub ar_v2_matches: Option<bool>,
+    pub test_crc_matches: Option<bool>,
+    pub copy_crc_matches: Option<bool>,
+    /// Whether the test and copy CRCs in the log agree. If not, the drive read the track differently
+    /// in the two passes, so the rip is unreliable even if the files still match the copy.
+    pub test_matches_copy: Option<bool>,
+}
+
+impl TrackComparison {
+    pub fn is_consistent(&self) -> bool {
+        self.length_matches != Some(false)
+            && self.ar_v1_matches != Some(false)
+            && self.ar_v2_matches != Some(false)
+            && self.test_crc_matches != Some(false)
+            && self.copy_crc_matches != Some(false)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LogComparison {
+    pub track_count_matches: bool,
+    pub tracks: Vec<TrackComparison>,
+}
+
+impl LogComparison {
+    /// Returns true if nothing in the log contradicts the local files.
+    pub fn is_consistent(&self) -> bool {
+        self.track_count_matches && self.tracks.iter().all(TrackComparison::is_consistent)
+    }
+}
+
+/// Compares a rip log against the frame lengths and checksums of the local files, in track order.
+pub fn compare_rip_log(rip_log: &RipLog, frame_lengths: &[FrameLength], local_tracks: &[LocalTrack]) -> LogComparison {
+    let track_count_matches = rip_log.toc.len() == frame_lengths.len();
+
+    let tracks = (0..frame_lengths.len()).map(|i| {
+        let track_num = i as u8 + 1;
+
+        let length_matches = rip_log.toc.iter()
+            .find(|e| e.track_num == track_num)
+            .map(|e| e.frame_length() == frame_lengths[i]);
+
+        let log_track = rip_log.tracks.iter().find(|t| t.track_num == track_num);
+        let local_track = local_tracks.get(i);
+
+        let ar_v1_matches = match (log_track.and_then(|t| t.ar_v1), local_track) {
+            (Some(ar_v1), Some(local_track)) => Some(ar_v1 == local_track.crcs.v1),
+            _ => None,
+        };
+        let ar_v2_matches = match (log_track.and_then(|t| t.ar_v2), local_track) {
+            (Some(ar_v2), Some(local_track)) => Some(ar_v2 == local_track.crcs.v2),
+            _ => None,
+        };
+        // Logs of rips without a copy pass still have a test CRC to compare against.
+        let crc32 = local_track.and_then(|t| t.copy_checksums.crc32);
+        let test_crc_matches = match (log_track.and_then(|t| t.test_crc), crc32) {
+            (Some(test_crc), Some(crc32)) => Some(test_crc == crc32),
+            _ => None,
+        };
+        let copy_crc_matches = match (log_track.and_then(|t| t.copy_crc), crc32) {
+            (Some(copy_crc), Some(crc32)) => Some(copy_crc == crc32),
+            _ => None,
+        };
+        let test_matches_copy = match log_track.map(|t| (t.test_crc, t.copy_crc)) {
+            Some((Some(test_crc), Some(copy_crc))) => Some(test_crc == copy_crc),
+            _ => None,
+        };
+
+        TrackComparison {
+            track_num,
+            length_matches,
+            ar_v1_matches,
+            ar_v2_matches,
+            test_crc_matches,
+            copy_crc_matches,
+            test_matches_copy,
+        }
+    }).collect();
+
+    LogComparison { track_count_matches, tracks }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crc::CRCs;
+    use checksum::CopyChecksums;
+    use decode::Md5Check;
+    use validate::LocalTrack;
+
+    use super::read_rip_log;
+    use super::compare_rip_log;
+    use super::RipLogKind;
+    use super::LogTrack;
+    use super::TocEntry;
+
+    const EXPECTED_LENGTHS: &[u64] = &[
+        24882,
+        21328,
+        25617,
+        19155,
+        16888,
+        25512,
+        23685,
+        20160,
+        23518,
+        16502,
+    ];
+
+    const EXPECTED_TRACKS: &[LogTrack] = &[
+        LogTrack { track_num: 1, test_crc: Some(0x7BBF6F8D), copy_crc: Some(0x7BBF6F8D), ar_v1: Some(0xDE813995), ar_v2: Some(0xF7D6BE16) },
+        LogTrack { track_num: 2, test_crc: Some(0x5A1B2C3D), copy_crc: Some(0x5A1B2C3D), ar_v1: Some(0x1C2D3E4F), ar_v2: Some(0xBD47E954) },
+    ];
+
+    #[test]
+    fn test_read_rip_log() {
+        let log_dir = PathBuf::from("test_util").join("input").join("log");
+
+        let inputs_and_expected = vec![
+            (log_dir.join("eac.log"), RipLogKind::Eac),
+            (log_dir.join("xld.log"), RipLogKind::Xld),
+            (log_dir.join("whipper.log"), RipLogKind::Whipper),
+        ];
+
+        for (input, expected_kind) in inputs_and_expected {
+            let produced = read_rip_log(input).unwrap();
+
+            assert_eq!(expected_kind, produced.kind);
+            assert_eq!(Some(30), produced.read_offset);
+            assert_eq!(EXPECTED_TRACKS.to_vec(), produced.tracks);
+
+            let lengths: Vec<_> = produced.toc.iter().map(TocEntry::frame_length).collect();
+            assert_eq!(EXPECTED_LENGTHS.to_vec(), lengths);
+        }
+    }
+
+    #[test]
+    fn test_compare_rip_log() {
+        let log_path = PathBuf::from("test_util").join("input").join("log").join("whipper.log");
+        let rip_log = read_rip_log(log_path).unwrap();
+
+        let local_track = |crcs, crc32| LocalTrack {
+            path: PathBuf::new(),
+            crcs,
+            copy_checksums: CopyChecksums { crc32, ..CopyChecksums::default() },
+            md5_check: Md5Check::Match,
+        };
+
+        let local_tracks = vec![
+            local_track(CRCs { v1: 0xDE813995, v2: 0xF7D6BE16 }, Some(0x7BBF6F8D)),
+            local_track(CRCs { v1: 0x1C2D3E4F, v2: 0x00000000 }, Some(0x00000000)),
+        ];
+
+        let produced = compare_rip_log(&rip_log, EXPECTED_LENGTHS, &local_tracks);
+
+        assert!(produced.track_count_matches);
+        assert!(produced.tracks[0].is_consistent());
+        assert_eq!(Some(true), produced.tracks[0].test_crc_matches);
+        assert_eq!(Some(true), produced.tracks[0].copy_crc_matches);
+        assert_eq!(Some(true), produced.tracks[0].test_matches_copy);
+        assert_eq!(Some(true), produced.tracks[1].ar_v1_matches);
+        assert_eq!(Some(false), produced.tracks[1].ar_v2_matches);
+        assert_eq!(Some(false), produced.tracks[1].copy_crc_matches);
+        assert_eq!(None, produced.tracks[2].ar_v1_matches);
+        assert!(!produced.is_consistent());
+
+        // A log whose test and copy CRCs differ describes an unreliable read, whichever one the files match.
+        let mut unreliable_log = rip_log.clone();
+        unreliable_log.tracks[0].test_crc = Some(0x12345678);
+
+        let produced = compare_rip_log(&unreliable_log, EXPECTED_LENGTHS, &local_tracks);
+        assert_eq!(Some(false), produced.tracks[0].test_matches_copy);
+        assert_eq!(Some(false), produced.tracks[0].test_crc_matches);
+        assert_eq!(Some(true), produced.tracks[0].copy_crc_matches);
+        assert!(!produced.tracks[0].is_consistent());
+
+        // Without a copy CRC, the test CRC is still compared.
+        let mut test_only_log = rip_log.clone();
+        test_only_log.tracks[0].copy_crc = None;
+
+        let produced = compare_rip_log(&test_only_log, EXPECTED_LENGTHS, &local_tracks);
+        assert_eq!(Some(true), produced.tracks[0].test_crc_matches);
+        assert_eq!(None, produced.tracks[0].copy_crc_matches);
+        assert!(produced.tracks[0].is_consistent());
+
+        let mut shortened = EXPECTED_LENGTHS.to_vec();
+        shortened[9] -= 1;
+
+        let produced = compare_rip_log(&rip_log, &shortened, &[]);
+        assert_eq!(Some(false), produced.tracks[9].length_matches);
+    }
+}
@@ -0,0 +1,395 @@
+//! Serves a local mirror over HTTP at the same URLs as the AccurateRip database, so it can be used as a drop-in replacement.
+//! Rippers record their results by POSTing the CRCs of a rip as JSON to the URL of its dBAR file.
+
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use failure;
+use serde_json;
+
+use offset::DiscInfo;
+use crc::CRCs;
+use fetch::ar_bin_path;
+use fetch::BinSource;
+use mirror::add_rip;
+use mirror::Mirror;
+
+/// Path that dBAR files are served under, matching the AccurateRip database.
+pub const URL_PREFIX: &str = "/accuraterip/";
+
+/// Largest request body accepted, far more than the CRCs of any disc need.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+const READ_TIMEOUT_SECS: u64 = 30;
+
+/// Body of a submission, holding the CRCs of every track of a rip in order.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Submission {
+    pub tracks: Vec<CRCs>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: u16, message: &str) -> Self {
+        Response { status, content_type: "text/plain", body: format!("{}\n", message).into_bytes() }
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+pub struct Server {
+    listener: TcpListener,
+    mirror: Arc<Mirror>,
+    /// Serializes submissions within this server, so that they do not wait on each other's lock files.
+    submit_lock: Arc<Mutex<()>>,
+    accept_submissions: bool,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A, mirror: Mirror, accept_submissions: bool) -> Result<Self, failure::Error> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            mirror: Arc::new(mirror),
+            submit_lock: Arc::new(Mutex::new(())),
+            accept_submissions,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, failure::Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Handles connections until the listener fails, each one in its own thread.
+    pub fn serve(&self) -> Result<(), failure::Error> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let mirror = Arc::clone(&self.mirror);
+            let submit_lock = Arc::clone(&self.submit_lock);
+            let accept_submissions = self.accept_submissions;
+
+            thread::spawn(move || {
+                if let Err(err) = handle_connection(stream, &mirror, &submit_lock, accept_submissions) {
+                    error!("{}", err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn handle_connection(stream: TcpStream, mirror: &Mirror, submit_lock: &Mutex<()>, accept_submissions: bool) -> Result<(), failure::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS)))?;
+
+    let response = match read_request(&mut BufReader::new(&stream)) {
+        Ok(Some(request)) => {
+            let response = handle_request(mirror, submit_lock, accept_submissions, &request);
+            info!("{} {} {}", request.method, request.path, response.status);
+            response
+        },
+        Ok(None) => return Ok(()),
+        Err(err) => Response::text(400, &err.to_string()),
+    };
+
+    write_response(&stream, &response)?;
+
+    Ok(())
+}
+
+/// Reads a single request, or returns `None` if the connection was closed without sending one.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, failure::Error> {
+    let mut request_line = String::new();
+
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = request_line.split_whitespace();
+
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/") => (method.to_string(), path.to_string()),
+        _ => bail!("malformed request line"),
+    };
+
+    let mut content_length = 0;
+
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 {
+            bail!("connection closed before end of headers");
+        }
+
+        let line = line.trim();
+
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(colon_pos) = line.find(':') {
+            let (name, value) = line.split_at(colon_pos);
+
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value[1..].trim().parse().map_err(|_| format_err!("invalid Content-Length"))?;
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_LEN {
+        bail!("request body is too large");
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request { method, path, body }))
+}
+
+fn write_response<W: Write>(mut writer: W, response: &Response) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len(),
+    )?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+/// Returns the disc that a request path refers to, e.g. `/accuraterip/f/a/a/dBAR-010-0012baaf-0094edfd-8b0b500a.bin`.
+/// The directories have to match the disc ID, so that only paths within the mirror can be requested.
+fn parse_bin_path(path: &str) -> Option<DiscInfo> {
+    if !path.starts_with(URL_PREFIX) {
+        return None;
+    }
+
+    let relative_path = &path[URL_PREFIX.len()..];
+    let file_name = relative_path.rsplit('/').next()?;
+
+    if !file_name.starts_with("dBAR-") || !file_name.ends_with(".bin") {
+        return None;
+    }
+
+    let disc_info = DiscInfo::from_ar_disc_id(&file_name["dBAR-".len()..file_name.len() - ".bin".len()]).ok()?;
+
+    if ar_bin_path(&disc_info) == relative_path { Some(disc_info) } else { None }
+}
+
+fn handle_request(mirror: &Mirror, submit_lock: &Mutex<()>, accept_submissions: bool, request: &Request) -> Response {
+    let disc_info = match parse_bin_path(&request.path) {
+        Some(disc_info) => disc_info,
+        None => return Response::text(404, "not found"),
+    };
+
+    match request.method.as_str() {
+        "GET" => match mirror.get_ar_bin(&disc_info) {
+            Ok(Some(ar_bin_data)) => Response { status: 200, content_type: "application/octet-stream", body: ar_bin_data },
+            Ok(None) => Response::text(404, "not found"),
+            Err(err) => Response::text(500, &err.to_string()),
+        },
+        "POST" if !accept_submissions => Response::text(403, "submissions are disabled"),
+        "POST" => {
+            let submission: Submission = match serde_json::from_slice(&request.body) {
+                Ok(submission) => submission,
+                Err(err) => return Response::text(400, &format!("invalid submission: {}", err)),
+            };
+
+            if submission.tracks.len() != disc_info.num_tracks as usize {
+                return Response::text(400, &format!("expected CRCs for {} tracks, got {}", disc_info.num_tracks, submission.tracks.len()));
+            }
+
+            let _guard = submit_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+            match add_rip(mirror, &disc_info, &submission.tracks).and_then(|o| Ok(serde_json::to_vec(&o)?)) {
+                Ok(body) => Response { status: 200, content_type: "application/json", body },
+                Err(err) => Response::text(500, &err.to_string()),
+            }
+        },
+        _ => Response::text(405, "method not allowed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json;
+
+    use self::tempfile::tempdir;
+
+    use offset::DiscInfo;
+    use crc::CRCs;
+    use fetch::unpack_ar_bin;
+    use fetch::BinSource;
+    use fetch::Fetcher;
+    use fetch::ProxySettings;
+    use fetch::ReqwestTransport;
+    use fetch::RetryPolicy;
+    use fetch::TrackResult;
+    use mirror::Mirror;
+
+    use super::parse_bin_path;
+    use super::Server;
+    use super::Submission;
+
+    const DISC_INFO: DiscInfo = DiscInfo {
+        id_1: 0x00000123,
+        id_2: 0x00000456,
+        cddb_id: 0x01000202,
+        num_tracks: 2,
+    };
+
+    const BIN_PATH: &str = "/accuraterip/3/2/1/dBAR-002-00000123-00000456-01000202.bin";
+
+    /// Starts a server on an unused local port, serving a mirror in a new temporary directory.
+    fn start_server(accept_submissions: bool) -> (SocketAddr, tempfile::TempDir) {
+        let dir = tempdir().unwrap();
+        let server = Server::bind("127.0.0.1:0", Mirror::new(dir.path()), accept_submissions).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::spawn(move || server.serve().unwrap());
+
+        (addr, dir)
+    }
+
+    /// Sends a request, and returns the status and body of the response.
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", method, path, body.len()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+
+        let header_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status_line = String::from_utf8_lossy(&response[..header_end]).lines().next().unwrap().to_string();
+        let status = status_line.split_whitespace().nth(1).unwrap().parse().unwrap();
+
+        (status, response[header_end + 4..].to_vec())
+    }
+
+    fn submission_body(crcs: &[(u32, u32)]) -> Vec<u8> {
+        let tracks = crcs.iter().map(|&(v1, v2)| CRCs { v1, v2 }).collect();
+        serde_json::to_vec(&Submission { tracks }).unwrap()
+    }
+
+    #[test]
+    fn test_parse_bin_path() {
+        let inputs_and_expected = vec![
+            (BIN_PATH, Some(DISC_INFO)),
+            ("/accuraterip/f/a/a/dBAR-010-0012baaf-0094edfd-8b0b500a.bin", Some(DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 10 })),
+            ("/accuraterip/3/2/2/dBAR-002-00000123-00000456-01000202.bin", None),
+            ("/accuraterip/dBAR-002-00000123-00000456-01000202.bin", None),
+            ("/accuraterip/../3/2/1/dBAR-002-00000123-00000456-01000202.bin", None),
+            ("/3/2/1/dBAR-002-00000123-00000456-01000202.bin", None),
+            ("/accuraterip/3/2/1/dBAR-002-00000123-00000456-01000202.txt", None),
+            ("/accuraterip/", None),
+        ];
+
+        for (input, expected) in inputs_and_expected {
+            let produced = parse_bin_path(input);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_server() {
+        let (addr, _dir) = start_server(true);
+
+        assert_eq!(404, request(addr, "GET", BIN_PATH, b"").0);
+
+        let (status, body) = request(addr, "POST", BIN_PATH, &submission_body(&[(1, 2), (3, 4)]));
+        assert_eq!(200, status);
+        assert_eq!(r#"{"outcome":"added","pressing":0}"#, String::from_utf8(body).unwrap());
+
+        let (status, body) = request(addr, "POST", BIN_PATH, &submission_body(&[(1, 2), (3, 4)]));
+        assert_eq!(200, status);
+        assert_eq!(r#"{"outcome":"confirmed","pressing":0}"#, String::from_utf8(body).unwrap());
+
+        let (status, body) = request(addr, "GET", BIN_PATH, b"");
+        assert_eq!(200, status);
+        assert_eq!(
+            vec![(DISC_INFO, vec![TrackResult { confidence: 2, crc: 2 }, TrackResult { confidence: 2, crc: 4 }])],
+            unpack_ar_bin(&body).unwrap(),
+        );
+
+        let bad_requests_and_expected = vec![
+            (("POST", BIN_PATH, submission_body(&[(1, 2)])), 400),
+            (("POST", BIN_PATH, b"{\"tracks\": 3}".to_vec()), 400),
+            (("PUT", BIN_PATH, submission_body(&[(1, 2), (3, 4)])), 405),
+            (("GET", "/accuraterip/../../etc/passwd", vec![]), 404),
+        ];
+
+        for ((method, path, body), expected) in bad_requests_and_expected {
+            let (produced, _) = request(addr, method, path, &body);
+            assert_eq!(expected, produced);
+        }
+    }
+
+    #[test]
+    fn test_server_read_only() {
+        let (addr, _dir) = start_server(false);
+
+        assert_eq!(403, request(addr, "POST", BIN_PATH, &submission_body(&[(1, 2), (3, 4)])).0);
+        assert_eq!(404, request(addr, "GET", BIN_PATH, b"").0);
+    }
+
+    #[test]
+    fn test_server_as_database() {
+        let (addr, dir) = start_server(true);
+        Mirror::new(dir.path()).put(&DISC_INFO, b"bin").unwrap();
+
+        let transport = ReqwestTransport::new(Duration::from_secs(10), ProxySettings::default(), "test-agent/1.0").unwrap();
+        let policy = RetryPolicy {
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+            min_interval: Duration::from_millis(0),
+        };
+        let fetcher = Fetcher::new(transport, &format!("http://{}/accuraterip", addr), None, policy);
+
+        assert_eq!(Some(b"bin".to_vec()), fetcher.get_ar_bin(&DISC_INFO).unwrap());
+        assert_eq!(None, fetcher.get_ar_bin(&DiscInfo { cddb_id: 0, ..DISC_INFO }).unwrap());
+    }
+}
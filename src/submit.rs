@@ -0,0 +1,177 @@
+//! Records rip results in the dBAR format, the same way AccurateRip aggregates submissions from rippers.
+//! This allows building a private database of discs, e.g. in a local mirror.
+
+use failure;
+
+use offset::DiscInfo;
+use crc::CRCs;
+use fetch::pack_ar_bin;
+use fetch::unpack_ar_bin;
+use fetch::TrackResult;
+
+/// Size of the header of each CRC set in a dBAR file: track count, both disc IDs and the CDDB ID.
+const CHUNK_HEADER_LEN: usize = 13;
+
+/// Size of each track result in a dBAR file: confidence, CRC and an unused field.
+const TRACK_RESULT_LEN: usize = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum MergeOutcome {
+    /// The CRCs matched an existing pressing, whose confidence was incremented.
+    Confirmed { pressing: usize },
+    /// The CRCs did not match any existing pressing, and were added as a new one.
+    Added { pressing: usize },
+}
+
+/// Packs the AccurateRip v2 CRCs of a rip into a dBAR file with a single CRC set, with a confidence of 1.
+pub fn pack_submission(disc_info: &DiscInfo, crcs: &[CRCs]) -> Result<Vec<u8>, failure::Error> {
+    let track_results = crcs.iter().map(|c| TrackResult { confidence: 1, crc: c.v2 }).collect();
+
+    pack_ar_bin(&[(*disc_info, track_results)])
+}
+
+/// Merges the CRCs of a rip into an existing dBAR file.
+/// If a pressing already holds the same v2 or v1 CRCs for every track, the confidence of its tracks is incremented.
+/// Otherwise the v2 CRCs are appended as a new pressing with a confidence of 1.
+/// Existing data is kept byte for byte, including the fields that `unpack_ar_bin` skips.
+pub fn merge_submission(ar_bin_data: &[u8], disc_info: &DiscInfo, crcs: &[CRCs]) -> Result<(Vec<u8>, MergeOutcome), failure::Error> {
+    if crcs.len() != disc_info.num_tracks as usize {
+        bail!("expected CRCs for {} tracks, got {}", disc_info.num_tracks, crcs.len());
+    }
+
+    let bin_results = unpack_ar_bin(ar_bin_data)?;
+
+    if let Some(&(ref other_disc_info, _)) = bin_results.iter().find(|&&(ref d, _)| d != disc_info) {
+        bail!("dBAR file is for disc {}, not {}", other_disc_info.ar_disc_id(), disc_info.ar_disc_id());
+    }
+
+    let matches_all = |track_results: &[TrackResult], version: fn(&CRCs) -> u32| {
+        track_results.iter().zip(crcs).all(|(t, c)| t.crc == version(c))
+    };
+
+    let matching_pressing = bin_results.iter().position(|&(_, ref t)| matches_all(t, |c| c.v2))
+        .or_else(|| bin_results.iter().position(|&(_, ref t)| matches_all(t, |c| c.v1)));
+
+    let mut merged = ar_bin_data.to_vec();
+
+    match matching_pressing {
+        Some(pressing) => {
+            // Every CRC set in the file is for the same disc, so they all have the same size.
+            let chunk_start = pressing * (CHUNK_HEADER_LEN + crcs.len() * TRACK_RESULT_LEN);
+
+            for track_index in 0..crcs.len() {
+                let confidence_pos = chunk_start + CHUNK_HEADER_LEN + track_index * TRACK_RESULT_LEN;
+                merged[confidence_pos] = merged[confidence_pos].saturating_add(1);
+            }
+
+            Ok((merged, MergeOutcome::Confirmed { pressing }))
+        },
+        None => {
+            merged.extend(pack_submission(disc_info, crcs)?);
+
+            Ok((merged, MergeOutcome::Added { pressing: bin_results.len() }))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use offset::DiscInfo;
+    use offset::SAMPLE_DISC_INFO;
+    use crc::CRCs;
+    use fetch::unpack_ar_bin;
+    use fetch::TrackResult;
+
+    use super::merge_submission;
+    use super::pack_submission;
+    use super::MergeOutcome;
+
+    use test_util::load_sample_bin;
+
+    fn crcs_from(v1: &[u32], v2: &[u32]) -> Vec<CRCs> {
+        v1.iter().zip(v2).map(|(&v1, &v2)| CRCs { v1, v2 }).collect()
+    }
+
+    #[test]
+    fn test_pack_submission() {
+        let crcs = crcs_from(&[10, 20], &[1, 2]);
+        let disc_info = DiscInfo { num_tracks: 2, ..SAMPLE_DISC_INFO };
+
+        let expected = vec![(disc_info, vec![TrackResult { confidence: 1, crc: 1 }, TrackResult { confidence: 1, crc: 2 }])];
+        let produced = unpack_ar_bin(&pack_submission(&disc_info, &crcs).unwrap()).unwrap();
+
+        assert_eq!(expected, produced);
+    }
+
+    #[test]
+    fn test_merge_submission() {
+        let ar_bin_data = load_sample_bin();
+        let bin_results = unpack_ar_bin(&ar_bin_data).unwrap();
+        let pressing_crcs = |p: usize| bin_results[p].1.iter().map(|t| t.crc).collect::<Vec<_>>();
+        let unknown_crcs = (0..10).collect::<Vec<u32>>();
+
+        let inputs_and_expected = vec![
+            // Pressing 1 holds v2 CRCs.
+            (crcs_from(&unknown_crcs, &pressing_crcs(1)), MergeOutcome::Confirmed { pressing: 1 }),
+            // Pressing 0 holds v1 CRCs.
+            (crcs_from(&pressing_crcs(0), &unknown_crcs), MergeOutcome::Confirmed { pressing: 0 }),
+            (crcs_from(&unknown_crcs, &unknown_crcs), MergeOutcome::Added { pressing: 9 }),
+        ];
+
+        for (crcs, expected) in inputs_and_expected {
+            let (merged, produced) = merge_submission(&ar_bin_data, &SAMPLE_DISC_INFO, &crcs).unwrap();
+            assert_eq!(expected, produced);
+
+            let merged_results = unpack_ar_bin(&merged).unwrap();
+
+            match produced {
+                MergeOutcome::Confirmed { pressing } => {
+                    assert_eq!(ar_bin_data.len(), merged.len());
+
+                    for (p, (original, merged)) in bin_results.iter().zip(&merged_results).enumerate() {
+                        for (original, merged) in original.1.iter().zip(&merged.1) {
+                            let expected_confidence = if p == pressing { original.confidence + 1 } else { original.confidence };
+                            assert_eq!(TrackResult { confidence: expected_confidence, crc: original.crc }, *merged);
+                        }
+                    }
+                },
+                MergeOutcome::Added { .. } => {
+                    assert_eq!(ar_bin_data[..], merged[..ar_bin_data.len()]);
+                    assert_eq!(bin_results.len() + 1, merged_results.len());
+
+                    let new_results = &merged_results.last().unwrap().1;
+                    assert!(new_results.iter().zip(&unknown_crcs).all(|(t, &c)| t.confidence == 1 && t.crc == c));
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_submission_empty() {
+        let crcs = crcs_from(&[10, 20], &[1, 2]);
+        let disc_info = DiscInfo { num_tracks: 2, ..SAMPLE_DISC_INFO };
+
+        let (merged, outcome) = merge_submission(&[], &disc_info, &crcs).unwrap();
+        assert_eq!(MergeOutcome::Added { pressing: 0 }, outcome);
+        assert_eq!(pack_submission(&disc_info, &crcs).unwrap(), merged);
+
+        let (merged, outcome) = merge_submission(&merged, &disc_info, &crcs).unwrap();
+        assert_eq!(MergeOutcome::Confirmed { pressing: 0 }, outcome);
+        assert!(unpack_ar_bin(&merged).unwrap()[0].1.iter().all(|t| t.confidence == 2));
+    }
+
+    #[test]
+    fn test_merge_submission_invalid() {
+        let ar_bin_data = load_sample_bin();
+
+        // Wrong number of tracks.
+        assert!(merge_submission(&ar_bin_data, &SAMPLE_DISC_INFO, &crcs_from(&[1], &[2])).is_err());
+
+        // dBAR file for a different disc.
+        let other_disc_info = DiscInfo { id_1: 1, ..SAMPLE_DISC_INFO };
+        let crcs = crcs_from(&[0; 10], &[0; 10]);
+        assert!(merge_submission(&ar_bin_data, &other_disc_info, &crcs).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Writes AccurateRip verification results back into FLAC Vorbis comments.
+//! Only the metadata blocks are rewritten, audio frames are copied through untouched.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use failure;
+use metaflac::Tag;
+use metaflac::block::BlockType;
+
+use offset::DiscInfo;
+use validate::TrackReport;
+use validate::ValidationReport;
+
+pub const RESULT_KEY: &str = "ACCURATERIPRESULT";
+pub const CRC_KEY: &str = "ACCURATERIPCRC";
+pub const V2_CRC_KEY: &str = "ACCURATERIPV2CRC";
+pub const CONFIDENCE_KEY: &str = "ACCURATERIPCONFIDENCE";
+pub const DISC_ID_KEY: &str = "ACCURATERIPDISCID";
+pub const OFFSET_KEY: &str = "ACCURATERIPOFFSET";
+
+/// Every key written by `write_tags`.
+const RESULT_KEYS: &[&str] = &[RESULT_KEY, CRC_KEY, V2_CRC_KEY, CONFIDENCE_KEY, DISC_ID_KEY, OFFSET_KEY];
+
+/// A single Vorbis comment that is (or would be, in a dry run) written to a file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TagChange {
+    pub path: PathBuf,
+    pub key: &'static str,
+    pub old_value: Option<String>,
+    pub new_value: String,
+}
+
+/// Produces the Vorbis comments that describe the verification result of a single track.
+pub fn track_tags(track: &TrackReport, disc_info: &DiscInfo) -> Vec<(&'static str, String)> {
+    vec![
+        (RESULT_KEY, track.status.description().to_string()),
+        (CRC_KEY, format!("{:0>8X}", track.crcs.v1)),
+        (V2_CRC_KEY, format!("{:0>8X}", track.crcs.v2)),
+        (CONFIDENCE_KEY, track.total_confidence.to_string()),
+        (DISC_ID_KEY, disc_info.ar_disc_id()),
+        (OFFSET_KEY, track.offset.to_string()),
+    ]
+}
+
+/// Writes the verification results of each track in a report into its FLAC file.
+/// If `dry_run` is set, the files are left alone and only the changes that would be made are returned.
+/// Comments that already hold the expected value are not reported as changes.
+pub fn write_tags(report: &ValidationReport, dry_run: bool) -> Result<Vec<TagChange>, failure::Error> {
+    let mut changes = vec![];
+
+    for track in &report.tracks {
+        let mut flac_tag = Tag::read_from_path(&track.path)?;
+        let mut track_changes = vec![];
+
+        for (key, new_value) in track_tags(track, &report.disc_info) {
+            let old_value = flac_tag.get_vorbis(key).and_then(|values| values.first().cloned());
+
+            if old_value.as_ref() == Some(&new_value) {
+                continue;
+            }
+
+            if !dry_run {
+                flac_tag.set_vorbis(key, vec![new_value.clone()]);
+            }
+
+            track_changes.push(TagChange { path: track.path.clone(), key, old_value, new_value });
+        }
+
+        if !dry_run && !track_changes.is_empty() {
+            flac_tag.save()?;
+        }
+
+        changes.extend(track_changes);
+    }
+
+    Ok(changes)
+}
+
+/// Copies the Vorbis comments and pictures of one FLAC file into another, replacing any comments it already has.
+/// Used to carry tags over to files that were re-encoded from modified audio.
+/// Verification results are left out, since they describe the audio before it was modified.
+pub fn copy_tags<P: AsRef<Path>, Q: AsRef<Path>>(src_path: P, dst_path: Q) -> Result<(), failure::Error> {
+    let src_tag = Tag::read_from_path(src_path)?;
+    let mut dst_tag = Tag::read_from_path(dst_path)?;
+
+    dst_tag.remove_blocks(BlockType::VorbisComment);
+
+    for block_type in &[BlockType::VorbisComment, BlockType::Picture] {
+        for block in src_tag.get_blocks(*block_type) {
+            dst_tag.push_block(block.clone());
+        }
+    }
+
+    for key in RESULT_KEYS {
+        dst_tag.remove_vorbis(key);
+    }
+
+    dst_tag.save()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use offset::DiscInfo;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::CopyChecksums;
+    use validate::TrackReport;
+    use validate::TrackStatus;
+    use validate::PressingMatch;
+    use validate::CRCVersion;
+
+    use super::track_tags;
+    use super::RESULT_KEYS;
+
+    #[test]
+    fn test_track_tags() {
+        let disc_info = DiscInfo { id_1: 1227439, id_2: 9760253, cddb_id: 2332774410, num_tracks: 10 };
+
+        let track = TrackReport {
+            track_num: 1,
+            path: PathBuf::from("01.flac"),
+            crcs: CRCs { v1: 0xf7d6be16, v2: 0x1a2b },
+            copy_checksums: CopyChecksums::default(),
+            md5_check: Md5Check::Match,
+            offset: 0,
+            matches: vec![
+                PressingMatch { pressing_index: 0, confidence: 122, crc_version: CRCVersion::V1 },
+                PressingMatch { pressing_index: 8, confidence: 2, crc_version: CRCVersion::V1 },
+            ],
+            matched_pressing: Some(0),
+            total_confidence: 124,
+            status: TrackStatus::Accurate,
+        };
+
+        let expected = vec![
+            ("ACCURATERIPRESULT", "accurate".to_string()),
+            ("ACCURATERIPCRC", "F7D6BE16".to_string()),
+            ("ACCURATERIPV2CRC", "00001A2B".to_string()),
+            ("ACCURATERIPCONFIDENCE", "124".to_string()),
+            ("ACCURATERIPDISCID", "010-0012baaf-0094edfd-8b0b500a".to_string()),
+            ("ACCURATERIPOFFSET", "0".to_string()),
+        ];
+
+        let produced = track_tags(&track, &disc_info);
+        assert_eq!(expected, produced);
+
+        // Keys left out when copying tags have to cover every key written.
+        assert!(produced.iter().all(|&(key, _)| RESULT_KEYS.contains(&key)));
+    }
+}
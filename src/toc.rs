@@ -0,0 +1,231 @@
+//! Parses textual TOC representations, so that disc IDs can be calculated without a full set of local files.
+
+use std::path::Path;
+
+use failure;
+
+use offset::FrameLength;
+use offset::FrameOffset;
+use offset::DiscInfo;
+use offset::get_frame_lengths;
+use offset::calc_frame_offsets;
+use offset::calc_disc_info_for_track_offsets;
+use riplog::parse_toc_table;
+
+/// Number of frames in the lead-in, which TOC formats based on absolute disc positions include.
+const LEAD_IN_FRAMES: u64 = 150;
+
+/// Track start offsets and lead-out offset of a disc, in frames from the start of track 1's index 00.
+/// Track 1 starts after frame 0 if its pregap contains audio (hidden track one audio, or HTOA).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Toc {
+    pub track_offsets: Vec<FrameOffset>,
+    pub leadout: FrameOffset,
+}
+
+impl Toc {
+    pub fn from_frame_lengths<II: IntoIterator<Item = FrameLength>>(frame_lengths: II) -> Self {
+        Toc::from_frame_lengths_with_pregap(0, frame_lengths)
+    }
+
+    /// Builds a TOC for a disc whose track 1 is preceded by `pregap_length` frames of audio.
+    pub fn from_frame_lengths_with_pregap<II: IntoIterator<Item = FrameLength>>(pregap_length: FrameLength, frame_lengths: II) -> Self {
+        let mut track_offsets = vec![pregap_length];
+        track_offsets.extend(calc_frame_offsets(frame_lengths).into_iter().map(|o| o + pregap_length));
+
+        let leadout = track_offsets.pop().unwrap_or(0);
+
+        Toc { track_offsets, leadout }
+    }
+
+    /// Builds a TOC from the lengths of the track files of a disc, and of its HTOA file if there is one.
+    /// The HTOA is not counted as a track, but shifts the offsets of all tracks.
+    pub fn from_files<P: AsRef<Path>>(htoa_path: Option<P>, flac_paths: &[P]) -> Result<Self, failure::Error> {
+        let pregap_length = match htoa_path {
+            Some(htoa_path) => get_frame_lengths(htoa_path)?,
+            None => 0,
+        };
+
+        let frame_lengths = flac_paths.iter().map(get_frame_lengths).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Toc::from_frame_lengths_with_pregap(pregap_length, frame_lengths))
+    }
+
+    pub fn num_tracks(&self) -> usize {
+        self.track_offsets.len()
+    }
+
+    /// Returns the length of a track, numbered from 1, or `None` if the disc has no such track.
+    pub fn track_frame_length(&self, track_num: usize) -> Option<FrameLength> {
+        let start = *self.track_offsets.get(track_num.checked_sub(1)?)?;
+        let end = self.track_offsets.get(track_num).cloned().unwrap_or(self.leadout);
+
+        Some(end - start)
+    }
+
+    /// Returns the number of audio frames before track 1, which is non-zero for discs with HTOA.
+    pub fn pregap_length(&self) -> FrameLength {
+        self.track_offsets.first().cloned().unwrap_or(0)
+    }
+
+    pub fn disc_info(&self) -> Result<DiscInfo, failure::Error> {
+        if self.track_offsets.is_empty() {
+            bail!("TOC contains no tracks");
+        }
+
+        let disc_info = calc_disc_info_for_track_offsets(&self.track_offsets, self.leadout);
+        debug!("disc ID {}", disc_info.ar_disc_id());
+
+        Ok(disc_info)
+    }
+
+    /// Builds a TOC from offsets that include the lead-in, as used by MusicBrainz and `cd-discid`.
+    fn from_absolute_offsets(track_offsets: &[u64], leadout: u64) -> Result<Self, failure::Error> {
+        let relative = |offset: u64| offset.checked_sub(LEAD_IN_FRAMES)
+            .ok_or_else(|| format_err!("offset {} lies within the lead-in", offset));
+
+        let track_offsets = track_offsets.iter().cloned().map(&relative).collect::<Result<Vec<_>, _>>()?;
+        let leadout = relative(leadout)?;
+
+        Toc::validated(track_offsets, leadout)
+    }
+
+    fn validated(track_offsets: Vec<FrameOffset>, leadout: FrameOffset) -> Result<Self, failure::Error> {
+        if track_offsets.is_empty() || track_offsets.len() > 99 {
+            bail!("invalid number of tracks: {}", track_offsets.len());
+        }
+
+        let mut all_offsets = track_offsets.iter().chain(Some(&leadout));
+        let mut prev = all_offsets.next().cloned().unwrap_or(0);
+
+        for &offset in all_offsets {
+            if offset <= prev {
+                bail!("TOC offsets are not strictly increasing");
+            }
+            prev = offset;
+        }
+
+        Ok(Toc { track_offsets, leadout })
+    }
+}
+
+/// Parses a TOC from any of the supported textual forms:
+/// * a MusicBrainz TOC string: `first last leadout offset...`, separated by spaces or `+`
+/// * the output of `cd-discid --musicbrainz`: `count offset... leadout`
+/// * the TOC table of an EAC or XLD log
+pub fn parse_toc(text: &str) -> Result<Toc, failure::Error> {
+    let table = parse_toc_table(text);
+
+    if !table.is_empty() {
+        let track_offsets = table.iter().map(|e| e.start_sector).collect();
+        let leadout = table.last().map(|e| e.end_sector + 1).unwrap_or(0);
+
+        return Toc::validated(track_offsets, leadout);
+    }
+
+    let tokens: Vec<&str> = text.split(|c: char| c.is_whitespace() || c == '+').filter(|t| !t.is_empty()).collect();
+
+    // Plain `cd-discid` output starts with the hex CDDB ID, and only records the disc length in seconds.
+    if tokens.first().map_or(false, |t| t.len() == 8 && u32::from_str_radix(t, 16).is_ok() && t.parse::<u64>().is_err()) {
+        bail!("cd-discid output only records the disc length in whole seconds, use `cd-discid --musicbrainz` instead");
+    }
+
+    let numbers = tokens.iter().map(|t| t.parse::<u64>()).collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format_err!("unrecognized TOC format"))?;
+
+    match numbers.len() {
+        // MusicBrainz TOC string.
+        n if n >= 4 && numbers[0] == 1 && n as u64 == 3 + numbers[1] => {
+            Toc::from_absolute_offsets(&numbers[3..], numbers[2])
+        },
+        // `cd-discid --musicbrainz` output.
+        n if n >= 3 && n as u64 == numbers[0] + 2 => {
+            Toc::from_absolute_offsets(&numbers[1..n - 1], numbers[n - 1])
+        },
+        _ => bail!("unrecognized TOC format"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use offset::DiscInfo;
+
+    use super::parse_toc;
+    use super::Toc;
+
+    use test_util::load_bytes;
+
+    const EXPECTED_LENGTHS: &[u64] = &[
+        24882,
+        21328,
+        25617,
+        19155,
+        16888,
+        25512,
+        23685,
+        20160,
+        23518,
+        16502,
+    ];
+
+    const EXPECTED_DISC_INFO: DiscInfo = DiscInfo {
+        id_1: 1227439,
+        id_2: 9760253,
+        cddb_id: 2332774410,
+        num_tracks: 10,
+    };
+
+    #[test]
+    fn test_parse_toc() {
+        let xld_log = String::from_utf8(load_bytes(PathBuf::from("test_util").join("input").join("log").join("xld.log"))).unwrap();
+
+        let inputs = vec![
+            "1 10 217397 150 25032 46360 71977 91132 108020 133532 157217 177377 200895".to_string(),
+            "1+10+217397+150+25032+46360+71977+91132+108020+133532+157217+177377+200895".to_string(),
+            "10 150 25032 46360 71977 91132 108020 133532 157217 177377 200895 217397\n".to_string(),
+            xld_log,
+        ];
+
+        let expected = Toc::from_frame_lengths(EXPECTED_LENGTHS.to_vec());
+
+        for input in inputs {
+            let produced = parse_toc(&input).unwrap();
+            assert_eq!(expected, produced);
+            assert_eq!(EXPECTED_DISC_INFO, produced.disc_info().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_toc_with_pregap() {
+        // Track 1 starts 32 frames into the disc.
+        let produced = parse_toc("1 10 217429 182 25064 46392 72009 91164 108052 133564 157249 177409 200927").unwrap();
+
+        let expected = Toc::from_frame_lengths_with_pregap(32, EXPECTED_LENGTHS.to_vec());
+        assert_eq!(expected, produced);
+        assert_eq!(32, produced.pregap_length());
+        assert_eq!(10, produced.num_tracks());
+        assert_eq!(Some(24882), produced.track_frame_length(1));
+        assert_eq!(Some(16502), produced.track_frame_length(10));
+        assert_eq!(None, produced.track_frame_length(0));
+        assert_eq!(None, produced.track_frame_length(11));
+        assert_ne!(EXPECTED_DISC_INFO, produced.disc_info().unwrap());
+    }
+
+    #[test]
+    fn test_parse_toc_invalid() {
+        let inputs = vec![
+            "8b0b500a 10 150 25032 46360 71977 91132 108020 133532 157217 177377 200895 2898",
+            "1 10 217397 150 25032",
+            "1 2 150 100 120",
+            "1 2 300 250 200",
+            "not a toc",
+            "",
+        ];
+
+        for input in inputs {
+            assert!(parse_toc(input).is_err());
+        }
+    }
+}
@@ -1,3 +1,16 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use failure;
+
+/// Numbers the temporary files of this process, so that concurrent writers never share one.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
 /// Sums the digits in a non-negative integer.
 pub fn sum_digits(n: u64) -> u64 {
     let mut r = 0u64;
@@ -11,6 +24,31 @@ pub fn sum_digits(n: u64) -> u64 {
     r
 }
 
+/// Writes a file by writing a temporary file next to it and renaming that into place.
+/// Readers never see a partially written file, and concurrent writers, even in other processes
+/// or on other hosts sharing the directory, each write their own temporary file, so the last rename wins.
+pub fn write_atomically<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), failure::Error> {
+    let path = path.as_ref();
+    let file_name = path.file_name().ok_or_else(|| format_err!("invalid file path: {}", path.display()))?;
+
+    let temp_name = format!(
+        ".{}.{}.{}.partial",
+        file_name.to_string_lossy(), process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst),
+    );
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temp_path);
+        bail!("unable to write {}: {}", path.display(), err);
+    }
+
+    Ok(())
+}
+
 /// Used in the lookahead iterator.
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum LookaheadPos {
@@ -125,7 +163,14 @@ impl<I: Iterator> LookaheadExt for I {}
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+
+    use self::tempfile::tempdir;
+
     use super::sum_digits;
+    use super::write_atomically;
 
     #[test]
     fn test_sum_digits() {
@@ -145,6 +190,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_write_atomically() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file.bin");
+
+        write_atomically(&path, &[1, 2, 3]).unwrap();
+        write_atomically(&path, &[4, 5]).unwrap();
+        assert_eq!(vec![4, 5], fs::read(&path).unwrap());
+
+        // No temporary files are left behind.
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        assert!(write_atomically(dir.path().join("missing").join("file.bin"), &[1]).is_err());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
     #[test]
     fn test_is_start() {
         use super::LookaheadPos;
@@ -1,54 +1,811 @@
+//! Validates a directory of FLAC files against the AccurateRip database.
+
+use std::str::FromStr;
 use std::path::Path;
+use std::path::PathBuf;
 
 use failure;
 
 use discovery::get_flac_files_in_dir;
-use offset::calc_disc_info_for_files;
-use fetch::get_ar_bin;
+use discovery::split_htoa_file;
+use offset::DiscInfo;
+use offset::get_frame_lengths;
+use toc::Toc;
 use fetch::unpack_ar_bin;
-use crc::calc_ar_crcs;
-use decode::decode_flac_file;
+use fetch::BinSource;
+use fetch::TrackResult;
+use crc::CRCs;
+use checksum::calc_checksums;
+use checksum::ChecksumSelection;
+use checksum::CopyChecksums;
+use decode::decode_and_verify_flac_file;
+use decode::get_decoded_len;
+use decode::Decoder;
+use decode::Md5Check;
+use config::Config;
+use config::ThresholdConfig;
 use util::LookaheadExt;
+use progress::Phase;
+use progress::Progress;
+use progress::ProgressListener;
+
+/// Which AccurateRip CRC algorithm produced a match.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CRCVersion {
+    V1,
+    V2,
+}
 
-pub fn validate<P: AsRef<Path>>(flac_dir: P) -> Result<(), failure::Error> {
-    let flac_files = get_flac_files_in_dir(flac_dir)?;
+/// A database pressing whose CRC for a given track matches the local CRC.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+pub struct PressingMatch {
+    pub pressing_index: usize,
+    pub confidence: u8,
+    pub crc_version: CRCVersion,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackStatus {
+    /// The decoded audio does not match the MD5 signature stored in the file.
+    /// This takes precedence over the AccurateRip result, since the CRCs were calculated from damaged audio.
+    Corrupted,
+    /// Matching pressings reach the high confidence threshold, and at least one of them matches the v2 CRC.
+    Accurate,
+    /// Matching pressings reach the minimum confidence threshold, but not the high one.
+    AccurateLowConfidence,
+    /// Matching pressings reach the high confidence threshold, but only match the older v1 CRC.
+    AccurateV1Only,
+    /// The disc is in the database, but no pressing has a matching CRC, or too few rips agree with it.
+    NotAccurate,
+    /// The disc is not in the database, so nothing could be compared.
+    Unknown,
+}
 
-    let disc_info = calc_disc_info_for_files(&flac_files)?;
+impl TrackStatus {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            TrackStatus::Corrupted => "file corrupted",
+            TrackStatus::Accurate => "accurate",
+            TrackStatus::AccurateLowConfidence => "accurate (low confidence)",
+            TrackStatus::AccurateV1Only => "accurate (v1 only)",
+            TrackStatus::NotAccurate => "not accurate",
+            TrackStatus::Unknown => "unknown",
+        }
+    }
+
+    pub fn is_accurate(&self) -> bool {
+        match *self {
+            TrackStatus::Accurate | TrackStatus::AccurateLowConfidence | TrackStatus::AccurateV1Only => true,
+            TrackStatus::Corrupted | TrackStatus::NotAccurate | TrackStatus::Unknown => false,
+        }
+    }
+
+    pub fn meets(&self, requirement: AccuracyRequirement) -> bool {
+        match requirement {
+            AccuracyRequirement::Any => self.is_accurate(),
+            AccuracyRequirement::HighConfidence => *self == TrackStatus::Accurate || *self == TrackStatus::AccurateV1Only,
+            AccuracyRequirement::HighConfidenceV2 => *self == TrackStatus::Accurate,
+        }
+    }
+}
 
-    let ar_bin_data = get_ar_bin(&disc_info)?;
+/// The weakest kind of accurate result that is still acceptable, e.g. for gating an archive workflow.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccuracyRequirement {
+    Any,
+    HighConfidence,
+    HighConfidenceV2,
+}
 
-    let bin_results = unpack_ar_bin(&ar_bin_data)?;
+impl FromStr for AccuracyRequirement {
+    type Err = failure::Error;
 
-    // Iterate over each item in bin results.
-    for bin_result in bin_results {
-        let (bin_disc_info, track_results) = bin_result;
-        println!("{:#?}", track_results);
-        assert_eq!(bin_disc_info, disc_info);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(AccuracyRequirement::Any),
+            "high-confidence" => Ok(AccuracyRequirement::HighConfidence),
+            "high-confidence-v2" => Ok(AccuracyRequirement::HighConfidenceV2),
+            _ => bail!("unknown accuracy requirement: {}", s),
+        }
     }
+}
 
-    for (lookahead_pos, flac_file) in flac_files.iter().lookahead() {
-        let samples = decode_flac_file(flac_file)?;
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct TrackReport {
+    pub track_num: u8,
+    pub path: PathBuf,
+    pub crcs: CRCs,
+    pub copy_checksums: CopyChecksums,
+    pub md5_check: Md5Check,
+    /// Sample offset applied to the audio before calculating CRCs.
+    pub offset: i32,
+    pub matches: Vec<PressingMatch>,
+    /// The pressing this track is attributed to, if any pressing matched.
+    /// This is the pressing shared by the whole disc where possible, otherwise the best match for this track.
+    pub matched_pressing: Option<usize>,
+    /// Sum of the confidence of every pressing that matched this track.
+    pub total_confidence: u32,
+    pub status: TrackStatus,
+}
 
-        let crcs = calc_ar_crcs(&samples, lookahead_pos.is_start(), lookahead_pos.is_end())?;
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticLevel {
+    Info,
+    Warning,
+}
 
-        println!("{:?}", crcs);
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn info<S: Into<String>>(message: S) -> Self {
+        Diagnostic { level: DiagnosticLevel::Info, message: message.into() }
     }
 
-    // println!("{:?}", disc_info);
+    pub fn warning<S: Into<String>>(message: S) -> Self {
+        Diagnostic { level: DiagnosticLevel::Warning, message: message.into() }
+    }
+}
 
-    Ok(())
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct ValidationReport {
+    pub disc_info: DiscInfo,
+    pub in_database: bool,
+    pub num_pressings: usize,
+    /// The single pressing that every matching track agrees with, if there is one.
+    pub matched_pressing: Option<usize>,
+    /// Set if matching tracks could not be attributed to a single pressing,
+    /// which usually means the rip was assembled from several discs.
+    pub mixed_pressings: bool,
+    pub tracks: Vec<TrackReport>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ValidationReport {
+    pub fn is_accurate(&self) -> bool {
+        self.tracks.iter().all(|t| t.status.is_accurate())
+    }
+
+    pub fn meets(&self, requirement: AccuracyRequirement) -> bool {
+        self.tracks.iter().all(|t| t.status.meets(requirement))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Verdict {
+    Accurate,
+    Inaccurate,
+    /// The disc is not in the database, so the rip could not be checked.
+    Unknown,
+}
+
+impl Verdict {
+    pub fn for_report(report: &ValidationReport, requirement: AccuracyRequirement) -> Self {
+        if !report.in_database {
+            Verdict::Unknown
+        }
+        else if report.meets(requirement) {
+            Verdict::Accurate
+        }
+        else {
+            Verdict::Inaccurate
+        }
+    }
+
+    /// Returns the name used for the verdict in output, as a directory name when moving albums, and for hooks.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Verdict::Accurate => "accurate",
+            Verdict::Inaccurate => "inaccurate",
+            Verdict::Unknown => "unknown",
+        }
+    }
+}
+
+/// Checksums calculated from a local track file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LocalTrack {
+    pub path: PathBuf,
+    pub crcs: CRCs,
+    pub copy_checksums: CopyChecksums,
+    pub md5_check: Md5Check,
+}
+
+/// Compares locally calculated CRCs against the unpacked contents of a dBAR file.
+/// If `bin_results` is `None`, the disc is assumed to not be present in the database.
+/// Tracks only count as accurate if the confidence of their matches adds up to at least the minimum threshold.
+pub fn build_report(
+    disc_info: DiscInfo,
+    local_tracks: Vec<LocalTrack>,
+    bin_results: Option<&[(DiscInfo, Vec<TrackResult>)]>,
+    thresholds: &ThresholdConfig,
+) -> ValidationReport
+{
+    let numbered_tracks = local_tracks.into_iter().enumerate().map(|(i, t)| (i as u8 + 1, t)).collect();
+
+    build_partial_report(disc_info, numbered_tracks, bin_results, thresholds)
+}
+
+/// Like `build_report`, but for some tracks of a disc, each given with its track number.
+pub fn build_partial_report(
+    disc_info: DiscInfo,
+    local_tracks: Vec<(u8, LocalTrack)>,
+    bin_results: Option<&[(DiscInfo, Vec<TrackResult>)]>,
+    thresholds: &ThresholdConfig,
+) -> ValidationReport
+{
+    let mut diagnostics = vec![];
+
+    let (in_database, bin_results) = match bin_results {
+        Some(bin_results) => (true, bin_results),
+        None => {
+            diagnostics.push(Diagnostic::warning("disc not present in AccurateRip database"));
+            (false, &[][..])
+        },
+    };
+
+    for (pressing_index, &(ref bin_disc_info, _)) in bin_results.iter().enumerate() {
+        if *bin_disc_info != disc_info {
+            diagnostics.push(Diagnostic::warning(
+                format!("pressing {} has mismatched disc info: {:?}", pressing_index, bin_disc_info)
+            ));
+        }
+    }
+
+    let mut tracks = vec![];
+
+    for (track_num, local_track) in local_tracks {
+        let LocalTrack { path, crcs, copy_checksums, md5_check } = local_track;
+        let track_index = track_num as usize - 1;
+        let mut matches = vec![];
+
+        for (pressing_index, &(ref bin_disc_info, ref track_results)) in bin_results.iter().enumerate() {
+            if *bin_disc_info != disc_info {
+                continue;
+            }
+
+            // Entries with zero confidence are padding for pressings with fewer submitted tracks.
+            let track_result = match track_results.get(track_index) {
+                Some(track_result) if track_result.confidence > 0 => track_result,
+                _ => continue,
+            };
+
+            let crc_version = if track_result.crc == crcs.v1 {
+                CRCVersion::V1
+            }
+            else if track_result.crc == crcs.v2 {
+                CRCVersion::V2
+            }
+            else {
+                continue;
+            };
+
+            matches.push(PressingMatch {
+                pressing_index,
+                confidence: track_result.confidence,
+                crc_version,
+            });
+        }
+
+        let total_confidence = matches.iter().map(|m| m.confidence as u32).sum();
+
+        let status = if md5_check == Md5Check::Mismatch {
+            TrackStatus::Corrupted
+        }
+        else if !in_database {
+            TrackStatus::Unknown
+        }
+        else if matches.is_empty() || total_confidence < thresholds.min_confidence {
+            TrackStatus::NotAccurate
+        }
+        else if total_confidence < thresholds.high_confidence {
+            TrackStatus::AccurateLowConfidence
+        }
+        else if matches.iter().all(|m| m.crc_version == CRCVersion::V1) {
+            TrackStatus::AccurateV1Only
+        }
+        else {
+            TrackStatus::Accurate
+        };
+
+        tracks.push(TrackReport {
+            track_num,
+            path,
+            crcs,
+            copy_checksums,
+            md5_check,
+            offset: 0,
+            matches,
+            matched_pressing: None,
+            total_confidence,
+            status,
+        });
+    }
+
+    let matched_pressing = find_common_pressing(&tracks);
+    let mixed_pressings = matched_pressing.is_none() && tracks.iter().any(|t| !t.matches.is_empty());
+
+    for track in &mut tracks {
+        track.matched_pressing = match matched_pressing {
+            Some(pressing_index) if !track.matches.is_empty() => Some(pressing_index),
+            _ => track.matches.iter().rev().max_by_key(|m| m.confidence).map(|m| m.pressing_index),
+        };
+    }
+
+    if mixed_pressings {
+        let pressings = tracks.iter()
+            .filter_map(|t| t.matched_pressing.map(|p| format!("track {}: {}", t.track_num, p)))
+            .collect::<Vec<_>>();
+
+        diagnostics.push(Diagnostic::warning(format!(
+            "tracks match different pressings ({}), the rip may have been assembled from several discs",
+            pressings.join(", "),
+        )));
+    }
+
+    ValidationReport {
+        disc_info,
+        in_database,
+        num_pressings: bin_results.len(),
+        matched_pressing,
+        mixed_pressings,
+        tracks,
+        diagnostics,
+    }
+}
+
+/// Finds the pressing that matches every track that has any match at all.
+/// If several pressings qualify, the one with the highest total confidence is chosen.
+fn find_common_pressing(tracks: &[TrackReport]) -> Option<usize> {
+    let mut matched_tracks = tracks.iter().filter(|t| !t.matches.is_empty());
+
+    let first_track = matched_tracks.next()?;
+    let mut candidates: Vec<usize> = first_track.matches.iter().map(|m| m.pressing_index).collect();
+
+    for track in matched_tracks {
+        candidates.retain(|&p| track.matches.iter().any(|m| m.pressing_index == p));
+    }
+
+    let pressing_confidence = |pressing_index: usize| -> u32 {
+        tracks.iter()
+            .flat_map(|t| t.matches.iter())
+            .filter(|m| m.pressing_index == pressing_index)
+            .map(|m| m.confidence as u32)
+            .sum()
+    };
+
+    // Ties go to the lower pressing index.
+    candidates.into_iter().rev().max_by_key(|&p| pressing_confidence(p))
+}
+
+/// The FLAC files of a single disc, along with the TOC and disc IDs derived from them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Album {
+    /// File holding hidden track one audio, which is excluded from the track count and CRCs.
+    pub htoa_file: Option<PathBuf>,
+    pub track_files: Vec<PathBuf>,
+    pub toc: Toc,
+    pub disc_info: DiscInfo,
+}
+
+pub fn identify_album<P: AsRef<Path>>(flac_dir: P) -> Result<Album, failure::Error> {
+    let (htoa_file, track_files) = split_htoa_file(get_flac_files_in_dir(flac_dir)?);
+
+    if track_files.is_empty() {
+        bail!("no FLAC files found");
+    }
+
+    let toc = Toc::from_files(htoa_file.as_ref(), &track_files.iter().collect::<Vec<_>>())?;
+    let disc_info = toc.disc_info()?;
+
+    Ok(Album { htoa_file, track_files, toc, disc_info })
+}
+
+/// Decodes each file of a complete disc in track order, verifies its MD5 signature and calculates its checksums.
+/// Each file is decoded only once; AccurateRip CRCs are always calculated, regardless of the selection.
+pub fn calc_track_crcs(
+    decoder: &Decoder,
+    flac_files: &[PathBuf],
+    selection: ChecksumSelection,
+    progress: &ProgressListener,
+) -> Result<Vec<LocalTrack>, failure::Error> {
+    let positions = flac_files.iter().lookahead()
+        .map(|(lookahead_pos, flac_file)| (flac_file, lookahead_pos.is_start(), lookahead_pos.is_end()))
+        .collect::<Vec<_>>();
+
+    calc_crcs_at_positions(decoder, &positions, selection, progress)
+}
+
+/// Like `calc_track_crcs`, but for some tracks of a disc, each given with its track number.
+/// Whether a track is the first or last one of the disc, which changes its AccurateRip CRCs,
+/// is decided by its track number instead of its position among the given files.
+pub fn calc_partial_track_crcs(
+    decoder: &Decoder,
+    tracks: &[(u8, PathBuf)],
+    num_tracks: u8,
+    selection: ChecksumSelection,
+    progress: &ProgressListener,
+) -> Result<Vec<LocalTrack>, failure::Error> {
+    let positions = tracks.iter()
+        .map(|&(track_num, ref flac_file)| (flac_file, track_num == 1, track_num == num_tracks))
+        .collect::<Vec<_>>();
+
+    calc_crcs_at_positions(decoder, &positions, selection, progress)
+}
+
+/// Calculates the size of the decoded audio of all given files, which progress updates are measured against.
+fn total_decoded_len<'a, I: IntoIterator<Item = &'a PathBuf>>(flac_files: I) -> Result<u64, failure::Error> {
+    flac_files.into_iter().map(get_decoded_len).sum()
+}
+
+/// Calculates checksums for files given with whether they are the first and last track of their disc.
+fn calc_crcs_at_positions(
+    decoder: &Decoder,
+    positions: &[(&PathBuf, bool, bool)],
+    selection: ChecksumSelection,
+    progress: &ProgressListener,
+) -> Result<Vec<LocalTrack>, failure::Error> {
+    let selection = ChecksumSelection { ar: true, ..selection };
+    let mut local_tracks = vec![];
+
+    let decoded_lens = positions.iter().map(|p| get_decoded_len(p.0)).collect::<Result<Vec<_>, _>>()?;
+    let mut state = Progress {
+        num_tracks: positions.len(),
+        bytes_total: decoded_lens.iter().sum(),
+        ..Progress::new(Phase::Decode)
+    };
+
+    for (&(flac_file, is_first, is_last), decoded_len) in positions.iter().zip(decoded_lens) {
+        let bytes_before = state.bytes_done;
+
+        state.track = Some(local_tracks.len() + 1);
+        state.phase = Phase::Decode;
+        progress.on_progress(&state);
+
+        let (samples, md5_check) = decode_and_verify_flac_file(decoder, flac_file, &mut |bytes_read| {
+            state.bytes_done = bytes_before + bytes_read;
+            progress.on_progress(&state);
+        })?;
+
+        state.phase = Phase::Checksum;
+        progress.on_progress(&state);
+
+        let checksums = calc_checksums(&samples, selection, is_first, is_last)?;
+        let crcs = checksums.ar.ok_or_else(|| format_err!("AccurateRip CRCs were not calculated"))?;
+        debug!("{}: AR v1 {:08X}, AR v2 {:08X}", flac_file.display(), crcs.v1, crcs.v2);
+
+        local_tracks.push(LocalTrack { path: flac_file.clone(), crcs, copy_checksums: checksums.copy, md5_check });
+
+        state.bytes_done = bytes_before + decoded_len;
+        progress.on_progress(&state);
+    }
+
+    Ok(local_tracks)
+}
+
+/// Validates a disc against dBAR files looked up in `source`, usually the fetcher for the configured database.
+/// The fetcher is passed in rather than created here, so that its rate limit covers every disc validated by a process.
+pub fn validate<P: AsRef<Path>>(
+    flac_dir: P,
+    selection: ChecksumSelection,
+    config: &Config,
+    source: &BinSource,
+    progress: &ProgressListener,
+) -> Result<ValidationReport, failure::Error> {
+    progress.on_progress(&Progress::new(Phase::Identify));
+
+    let album = identify_album(flac_dir)?;
+    let disc_info = album.disc_info;
+
+    let num_tracks = album.track_files.len();
+    let bytes_total = total_decoded_len(&album.track_files)?;
+
+    progress.on_progress(&Progress { num_tracks, bytes_total, ..Progress::new(Phase::Fetch) });
+
+    let ar_bin_data = source.get_ar_bin(&disc_info)?;
+
+    let bin_results = match ar_bin_data {
+        Some(ref ar_bin_data) => Some(unpack_ar_bin(ar_bin_data)?),
+        None => None,
+    };
+
+    let local_tracks = calc_track_crcs(&config.decoder, &album.track_files, selection, progress)?;
+
+    progress.on_progress(&Progress { num_tracks, bytes_done: bytes_total, bytes_total, ..Progress::new(Phase::Match) });
+
+    let mut report = build_report(
+        disc_info,
+        local_tracks,
+        bin_results.as_ref().map(Vec::as_slice),
+        &config.thresholds,
+    );
+
+    if let Some(ref htoa_file) = album.htoa_file {
+        report.diagnostics.push(Diagnostic::info(format!(
+            "hidden track one audio in {} ({} frames) is excluded from AccurateRip CRCs",
+            htoa_file.display(), album.toc.pregap_length(),
+        )));
+    }
+
+    Ok(report)
+}
+
+/// Validates some tracks of a disc against a TOC obtained separately, e.g. from a rip log.
+/// This allows checking the tracks that are left of a disc, since the disc ID cannot be calculated from them alone.
+pub fn validate_partial(
+    tracks: &[(u8, PathBuf)],
+    toc: &Toc,
+    selection: ChecksumSelection,
+    config: &Config,
+    source: &BinSource,
+    progress: &ProgressListener,
+) -> Result<ValidationReport, failure::Error> {
+    progress.on_progress(&Progress::new(Phase::Identify));
+
+    let disc_info = toc.disc_info()?;
+    let mut seen_tracks = vec![];
+
+    // A wrong track number would silently give wrong CRCs, so each file has to fit its place in the TOC.
+    for &(track_num, ref flac_file) in tracks {
+        let toc_length = match toc.track_frame_length(track_num as usize) {
+            Some(toc_length) => toc_length,
+            None => bail!("TOC has no track {}, it only has {} tracks", track_num, toc.num_tracks()),
+        };
+
+        if seen_tracks.contains(&track_num) {
+            bail!("more than one file given for track {}", track_num);
+        }
+        seen_tracks.push(track_num);
+
+        let file_length = get_frame_lengths(flac_file)?;
+
+        if file_length != toc_length {
+            bail!(
+                "{} is {} frames long, but track {} in the TOC is {} frames long",
+                flac_file.display(), file_length, track_num, toc_length,
+            );
+        }
+    }
+
+    let num_tracks = tracks.len();
+    let bytes_total = total_decoded_len(tracks.iter().map(|t| &t.1))?;
+
+    progress.on_progress(&Progress { num_tracks, bytes_total, ..Progress::new(Phase::Fetch) });
+
+    let bin_results = match source.get_ar_bin(&disc_info)? {
+        Some(ref ar_bin_data) => Some(unpack_ar_bin(ar_bin_data)?),
+        None => None,
+    };
+
+    let local_tracks = calc_partial_track_crcs(&config.decoder, tracks, disc_info.num_tracks, selection, progress)?;
+
+    progress.on_progress(&Progress { num_tracks, bytes_done: bytes_total, bytes_total, ..Progress::new(Phase::Match) });
+
+    let numbered_tracks = tracks.iter().map(|t| t.0).zip(local_tracks).collect();
+    let mut report = build_partial_report(disc_info, numbered_tracks, bin_results.as_ref().map(Vec::as_slice), &config.thresholds);
+
+    if tracks.len() < toc.num_tracks() {
+        report.diagnostics.push(Diagnostic::info(format!(
+            "only {} of {} tracks of the disc were verified", tracks.len(), toc.num_tracks(),
+        )));
+    }
+
+    Ok(report)
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
+    use offset::SAMPLE_DISC_INFO;
+    use fetch::unpack_ar_bin;
+    use crc::CRCs;
+    use decode::Md5Check;
+    use checksum::ChecksumSelection;
+    use checksum::CopyChecksums;
+    use config::Config;
+    use config::ThresholdConfig;
+    use progress::NoProgress;
+
     use super::validate;
+    use super::LocalTrack;
+    use super::build_report;
+    use super::build_partial_report;
+    use super::AccuracyRequirement;
+    use super::CRCVersion;
+    use super::DiagnosticLevel;
+    use super::PressingMatch;
+    use super::TrackStatus;
+
+    use test_util::load_sample_bin;
+
+    fn local_track(path: &str, crcs: CRCs, md5_check: Md5Check) -> LocalTrack {
+        LocalTrack { path: PathBuf::from(path), crcs, copy_checksums: CopyChecksums::default(), md5_check }
+    }
 
     #[test]
     fn test_create_ar_bin_url() {
         let flac_dir = PathBuf::from("test_util").join("input").join("flac");
 
-        validate(&flac_dir);
+        let config = Config::default();
+        let _ = config.fetcher().and_then(|fetcher| validate(&flac_dir, ChecksumSelection::all(), &config, &fetcher, &NoProgress));
+    }
+
+    #[test]
+    fn test_build_report() {
+        let bin_results = unpack_ar_bin(&load_sample_bin()).unwrap();
+
+        let local_tracks = vec![
+            // Matches pressing 0 as a v1 CRC.
+            local_track("01.flac", CRCs { v1: 4158045718, v2: 1 }, Md5Check::Match),
+            // Matches pressing 1 as a v2 CRC.
+            local_track("02.flac", CRCs { v1: 2, v2: 3824549311 }, Md5Check::Unavailable),
+            // Matches nothing.
+            local_track("03.flac", CRCs { v1: 3, v2: 4 }, Md5Check::Match),
+            // Matches pressing 0, but the file is damaged.
+            local_track("04.flac", CRCs { v1: 1209064292, v2: 5 }, Md5Check::Mismatch),
+        ];
+
+        let produced = build_report(SAMPLE_DISC_INFO, local_tracks, Some(&bin_results), &ThresholdConfig::default());
+
+        assert!(produced.in_database);
+        assert_eq!(9, produced.num_pressings);
+        assert!(!produced.is_accurate());
+
+        // Tracks 1 and 4 match pressing 0, but track 2 only matches pressing 1.
+        assert_eq!(None, produced.matched_pressing);
+        assert!(produced.mixed_pressings);
+        assert_eq!(1, produced.diagnostics.len());
+        assert_eq!(DiagnosticLevel::Warning, produced.diagnostics[0].level);
+
+        let expected = vec![
+            (
+                TrackStatus::AccurateV1Only,
+                vec![
+                    PressingMatch { pressing_index: 0, confidence: 122, crc_version: CRCVersion::V1 },
+                ],
+                Some(0),
+            ),
+            (
+                TrackStatus::Accurate,
+                vec![
+                    PressingMatch { pressing_index: 1, confidence: 120, crc_version: CRCVersion::V2 },
+                ],
+                Some(1),
+            ),
+            (
+                TrackStatus::NotAccurate,
+                vec![],
+                None,
+            ),
+            (
+                TrackStatus::Corrupted,
+                vec![
+                    PressingMatch { pressing_index: 0, confidence: 123, crc_version: CRCVersion::V1 },
+                ],
+                Some(0),
+            ),
+        ];
+
+        for (track, (expected_status, expected_matches, expected_pressing)) in produced.tracks.into_iter().zip(expected) {
+            assert_eq!(expected_status, track.status);
+            assert_eq!(expected_matches, track.matches);
+            assert_eq!(expected_pressing, track.matched_pressing);
+        }
+    }
+
+    #[test]
+    fn test_build_report_common_pressing() {
+        let bin_results = unpack_ar_bin(&load_sample_bin()).unwrap();
+
+        let local_tracks = vec![
+            // Matches pressing 0 as a v1 CRC, and pressing 8 as a v2 CRC.
+            local_track("01.flac", CRCs { v1: 4158045718, v2: 4256079886 }, Md5Check::Match),
+            // Matches pressing 0 only.
+            local_track("02.flac", CRCs { v1: 3175593300, v2: 1 }, Md5Check::Match),
+        ];
+
+        let produced = build_report(SAMPLE_DISC_INFO, local_tracks, Some(&bin_results), &ThresholdConfig::default());
+
+        assert_eq!(Some(0), produced.matched_pressing);
+        assert!(!produced.mixed_pressings);
+        assert!(produced.diagnostics.is_empty());
+
+        let expected = vec![(Some(0), 124), (Some(0), 123)];
+
+        for (track, (expected_pressing, expected_confidence)) in produced.tracks.into_iter().zip(expected) {
+            assert_eq!(expected_pressing, track.matched_pressing);
+            assert_eq!(expected_confidence, track.total_confidence);
+        }
+    }
+
+    #[test]
+    fn test_build_partial_report() {
+        let bin_results = unpack_ar_bin(&load_sample_bin()).unwrap();
+
+        // Track 2 of pressing 0 as a v1 CRC, which is only found when compared against the right track.
+        let crcs = CRCs { v1: 3175593300, v2: 1 };
+
+        let produced = build_partial_report(
+            SAMPLE_DISC_INFO,
+            vec![(2, local_track("02.flac", crcs, Md5Check::Match))],
+            Some(&bin_results),
+            &ThresholdConfig::default(),
+        );
+
+        assert_eq!(1, produced.tracks.len());
+        assert_eq!(2, produced.tracks[0].track_num);
+        assert_eq!(TrackStatus::AccurateV1Only, produced.tracks[0].status);
+        assert_eq!(Some(0), produced.matched_pressing);
+
+        let produced = build_report(SAMPLE_DISC_INFO, vec![local_track("02.flac", crcs, Md5Check::Match)], Some(&bin_results), &ThresholdConfig::default());
+        assert_eq!(TrackStatus::NotAccurate, produced.tracks[0].status);
+    }
+
+    #[test]
+    fn test_build_report_not_in_database() {
+        let local_tracks = vec![local_track("01.flac", CRCs { v1: 1, v2: 2 }, Md5Check::Match)];
+
+        let produced = build_report(SAMPLE_DISC_INFO, local_tracks, None, &ThresholdConfig::default());
+
+        assert!(!produced.in_database);
+        assert_eq!(None, produced.matched_pressing);
+        assert!(!produced.mixed_pressings);
+        assert_eq!(1, produced.diagnostics.len());
+        assert_eq!(TrackStatus::Unknown, produced.tracks[0].status);
+    }
+
+    #[test]
+    fn test_build_report_thresholds() {
+        let bin_results = unpack_ar_bin(&load_sample_bin()).unwrap();
+
+        let inputs_and_expected = vec![
+            // Matches pressing 0 with a confidence of 122 as a v1 CRC.
+            ((CRCs { v1: 4158045718, v2: 1 }, 1, 10), TrackStatus::AccurateV1Only),
+            ((CRCs { v1: 4158045718, v2: 1 }, 1, 123), TrackStatus::AccurateLowConfidence),
+            ((CRCs { v1: 4158045718, v2: 1 }, 123, 200), TrackStatus::NotAccurate),
+            // Matches pressing 1 with a confidence of 119 as a v2 CRC.
+            ((CRCs { v1: 1, v2: 3733010837 }, 1, 119), TrackStatus::Accurate),
+            // Matches pressing 8 with a confidence of 2 as a v1 CRC.
+            ((CRCs { v1: 4256079886, v2: 1 }, 1, 10), TrackStatus::AccurateLowConfidence),
+            ((CRCs { v1: 4256079886, v2: 1 }, 5, 10), TrackStatus::NotAccurate),
+        ];
+
+        for ((crcs, min_confidence, high_confidence), expected) in inputs_and_expected {
+            let thresholds = ThresholdConfig { min_confidence, high_confidence, ..ThresholdConfig::default() };
+            let local_tracks = vec![local_track("01.flac", crcs, Md5Check::Match)];
+            let produced = build_report(SAMPLE_DISC_INFO, local_tracks, Some(&bin_results), &thresholds);
+
+            assert_eq!(expected, produced.tracks[0].status);
+            assert_eq!(1, produced.tracks[0].matches.len());
+        }
+    }
+
+    #[test]
+    fn test_track_status_meets() {
+        let inputs_and_expected = vec![
+            (TrackStatus::Accurate, (true, true, true)),
+            (TrackStatus::AccurateV1Only, (true, true, false)),
+            (TrackStatus::AccurateLowConfidence, (true, false, false)),
+            (TrackStatus::NotAccurate, (false, false, false)),
+            (TrackStatus::Corrupted, (false, false, false)),
+            (TrackStatus::Unknown, (false, false, false)),
+        ];
+
+        for (input, (expected_any, expected_high, expected_high_v2)) in inputs_and_expected {
+            assert_eq!(expected_any, input.meets(AccuracyRequirement::Any));
+            assert_eq!(expected_high, input.meets(AccuracyRequirement::HighConfidence));
+            assert_eq!(expected_high_v2, input.meets(AccuracyRequirement::HighConfidenceV2));
+        }
+
+        assert_eq!(AccuracyRequirement::HighConfidenceV2, "high-confidence-v2".parse().unwrap());
+        assert!("perfect".parse::<AccuracyRequirement>().is_err());
     }
 }
@@ -0,0 +1,444 @@
+//! Watches an inbox directory that rippers drop albums into, and validates each album once its files stop changing.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use failure;
+use notify;
+use notify::RecursiveMode;
+use notify::Watcher;
+
+use checksum::ChecksumSelection;
+use config::Config;
+use discovery::find_album_dirs;
+use fetch::BinSource;
+use hooks::run_hooks;
+use progress::ProgressListener;
+use report::save_report;
+use report::REPORT_FILE_NAME;
+use tag::write_tags;
+use validate::identify_album;
+use validate::validate;
+use validate::AccuracyRequirement;
+use validate::Verdict;
+
+/// Size and modification time of every file directly within an album directory, sorted by path.
+pub type Fingerprint = Vec<(PathBuf, u64, Option<SystemTime>)>;
+
+pub fn album_fingerprint<P: AsRef<Path>>(album_dir: P) -> Result<Fingerprint, failure::Error> {
+    let mut fingerprint = vec![];
+
+    for entry in fs::read_dir(album_dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+
+        if metadata.is_file() {
+            fingerprint.push((entry.path(), metadata.len(), metadata.modified().ok()));
+        }
+    }
+
+    fingerprint.sort();
+
+    Ok(fingerprint)
+}
+
+/// Finds the albums in an inbox that have not been processed yet, i.e. that have no report.
+pub fn scan_inbox<P: AsRef<Path>>(inbox_dir: P) -> Result<Vec<(PathBuf, Fingerprint)>, failure::Error> {
+    let mut albums = vec![];
+
+    for album_dir in find_album_dirs(inbox_dir)? {
+        if !album_dir.join(REPORT_FILE_NAME).exists() {
+            let fingerprint = album_fingerprint(&album_dir)?;
+            albums.push((album_dir, fingerprint));
+        }
+    }
+
+    Ok(albums)
+}
+
+/// Tracks how long the files of each album have been unchanged, to tell when a ripper has finished writing it.
+pub struct StabilityTracker {
+    settle: Duration,
+    /// Albums waiting to settle, with the time their fingerprint was first seen.
+    pending: HashMap<PathBuf, (Fingerprint, Instant)>,
+    /// Albums already handed out, which are only handed out again if their files change.
+    done: HashMap<PathBuf, Fingerprint>,
+}
+
+impl StabilityTracker {
+    pub fn new(settle: Duration) -> Self {
+        StabilityTracker { settle, pending: HashMap::new(), done: HashMap::new() }
+    }
+
+    /// Updates the tracker with the albums currently in the inbox, and returns those that have settled.
+    pub fn update(&mut self, scanned: Vec<(PathBuf, Fingerprint)>, now: Instant) -> Vec<PathBuf> {
+        let mut pending = HashMap::new();
+        let mut done = HashMap::new();
+        let mut settled = vec![];
+
+        for (album_dir, fingerprint) in scanned {
+            if self.done.get(&album_dir) == Some(&fingerprint) {
+                done.insert(album_dir, fingerprint);
+                continue;
+            }
+
+            let since = match self.pending.remove(&album_dir) {
+                Some((ref old_fingerprint, since)) if *old_fingerprint == fingerprint => since,
+                _ => now,
+            };
+
+            if now.duration_since(since) >= self.settle {
+                settled.push(album_dir.clone());
+                done.insert(album_dir, fingerprint);
+            }
+            else {
+                pending.insert(album_dir, (fingerprint, since));
+            }
+        }
+
+        // Albums that disappeared from the inbox are forgotten.
+        self.pending = pending;
+        self.done = done;
+
+        settled.sort();
+        settled
+    }
+
+    /// Returns how long until the next pending album may settle, or `None` if no album is pending.
+    pub fn next_deadline(&self, now: Instant) -> Option<Duration> {
+        self.pending.values()
+            .map(|&(_, since)| {
+                let deadline = since + self.settle;
+                if deadline > now { deadline - now } else { Duration::from_secs(0) }
+            })
+            .min()
+    }
+}
+
+/// What to do with an album once it has been validated. A report is written into the album in either case.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Action {
+    /// Leaves the album in place, and writes the results into the tags of its files.
+    Label,
+    /// Moves the album into a subdirectory of the given directory named after the verdict.
+    /// An album directly in the inbox is labeled instead, since the inbox itself must stay in place.
+    MoveTo(PathBuf),
+}
+
+/// Returns where an album is moved to, keeping its path relative to the inbox.
+/// Returns `None` if the album is the inbox itself, which cannot be moved without losing the watch on it.
+pub fn move_target<P: AsRef<Path>, Q: AsRef<Path>, R: AsRef<Path>>(album_dir: P, inbox_dir: Q, dest_dir: R, verdict: Verdict) -> Option<PathBuf> {
+    match album_dir.as_ref().strip_prefix(inbox_dir) {
+        Ok(relative_dir) if relative_dir.components().next().is_some() => {
+            Some(dest_dir.as_ref().join(verdict.name()).join(relative_dir))
+        },
+        _ => None,
+    }
+}
+
+/// Validates an album, writes its report, applies the action and runs the hooks.
+/// Returns the verdict and where the album now is.
+pub fn process_album<P: AsRef<Path>, Q: AsRef<Path>>(
+    album_dir: P,
+    inbox_dir: Q,
+    action: &Action,
+    config: &Config,
+    source: &BinSource,
+    progress: &ProgressListener,
+) -> Result<(Verdict, PathBuf), failure::Error> {
+    let album_dir = album_dir.as_ref();
+
+    let report = validate(album_dir, ChecksumSelection::all(), config, source, progress)?;
+    let album = identify_album(album_dir)?;
+    let verdict = Verdict::for_report(&report, config.thresholds.require.unwrap_or(AccuracyRequirement::Any));
+
+    save_report(album_dir.join(REPORT_FILE_NAME), &album.toc, &report, config.report.key())?;
+
+    let target = match *action {
+        Action::Label => None,
+        Action::MoveTo(ref dest_dir) => {
+            let target = move_target(album_dir, inbox_dir, dest_dir, verdict);
+
+            if target.is_none() {
+                warn!("{}: album is directly in the inbox, so it is labeled instead of moved", album_dir.display());
+            }
+
+            target
+        },
+    };
+
+    let location = match target {
+        None => {
+            write_tags(&report, false)?;
+            album_dir.to_path_buf()
+        },
+        Some(target) => {
+            if target.exists() {
+                bail!("unable to move album, {} already exists", target.display());
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // Renaming only works within a file system, which keeps the move atomic.
+            fs::rename(album_dir, &target)
+                .map_err(|err| format_err!("unable to move album to {}: {}", target.display(), err))?;
+
+            target
+        },
+    };
+
+    run_hooks(&config.hooks, &location, verdict, &report)?;
+
+    Ok((verdict, location))
+}
+
+pub struct WatchOptions {
+    pub action: Action,
+    /// How long the files of an album must stay unchanged before it is validated.
+    pub settle: Duration,
+    /// Interval between scans of the inbox when file system notifications are not used.
+    pub poll_interval: Duration,
+    /// Whether to wait for file system notifications instead of scanning the inbox periodically.
+    pub notifications: bool,
+}
+
+/// Scans an inbox for settled albums, and waits for changes between scans.
+struct InboxWatch<'a> {
+    inbox_dir: &'a Path,
+    poll_interval: Duration,
+    tracker: StabilityTracker,
+    /// Watcher sending notifications to `rx`, `None` when polling.
+    watcher: Option<notify::RecommendedWatcher>,
+    rx: mpsc::Receiver<notify::RawEvent>,
+}
+
+impl<'a> InboxWatch<'a> {
+    fn new(inbox_dir: &'a Path, options: &WatchOptions) -> Self {
+        let (tx, rx) = mpsc::channel();
+
+        let watcher = if options.notifications {
+            match notify::raw_watcher(tx).and_then(|mut watcher| watcher.watch(inbox_dir, RecursiveMode::Recursive).map(|_| watcher)) {
+                Ok(watcher) => Some(watcher),
+                Err(err) => {
+                    warn!("unable to watch {} for changes, polling instead: {}", inbox_dir.display(), err);
+                    None
+                },
+            }
+        }
+        else {
+            None
+        };
+
+        InboxWatch { inbox_dir, poll_interval: options.poll_interval, tracker: StabilityTracker::new(options.settle), watcher, rx }
+    }
+
+    /// Scans the inbox, and returns the albums that have settled since the last scan.
+    fn settled_albums(&mut self) -> Vec<PathBuf> {
+        let scanned = match scan_inbox(self.inbox_dir) {
+            Ok(scanned) => scanned,
+            Err(err) => {
+                // Albums being moved around while scanning can fail a scan, which the next one recovers from.
+                warn!("unable to scan {}: {}", self.inbox_dir.display(), err);
+                vec![]
+            },
+        };
+
+        self.tracker.update(scanned, Instant::now())
+    }
+
+    /// Waits until the inbox may have changed, or until a pending album may have settled.
+    fn wait(&mut self) {
+        let next_deadline = self.tracker.next_deadline(Instant::now());
+
+        if self.watcher.is_none() {
+            thread::sleep(next_deadline.map_or(self.poll_interval, |d| d.min(self.poll_interval)));
+            return;
+        }
+
+        let event = match next_deadline {
+            Some(timeout) => self.rx.recv_timeout(timeout),
+            None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match event {
+            Ok(event) => debug!("change in inbox: {:?}", event.path),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                warn!("stopped receiving notifications for {}, polling instead", self.inbox_dir.display());
+                self.watcher = None;
+            },
+        }
+
+        // Changes arriving in a burst, e.g. while a ripper writes a file, only need a single scan.
+        while self.rx.try_recv().is_ok() {}
+    }
+}
+
+/// Watches an inbox until an error occurs, validating and handling each album once it has settled.
+/// Falls back to polling if the inbox cannot be watched for notifications, e.g. on some network file systems.
+pub fn watch<P: AsRef<Path>>(
+    inbox_dir: P,
+    options: &WatchOptions,
+    config: &Config,
+    source: &BinSource,
+    progress: &ProgressListener,
+) -> Result<(), failure::Error> {
+    let inbox_dir = inbox_dir.as_ref();
+    let mut inbox_watch = InboxWatch::new(inbox_dir, options);
+
+    loop {
+        for album_dir in inbox_watch.settled_albums() {
+            info!("album settled: {}", album_dir.display());
+
+            let result = process_album(&album_dir, inbox_dir, &options.action, config, source, progress);
+            progress.finish();
+
+            match result {
+                Ok((verdict, location)) => println!("{:<12}  {}", verdict.name(), location.display()),
+                Err(err) => eprintln!("error: {}: {}", album_dir.display(), err),
+            }
+        }
+
+        inbox_watch.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    use self::tempfile::tempdir;
+
+    use validate::Verdict;
+
+    use super::move_target;
+    use super::scan_inbox;
+    use super::Action;
+    use super::InboxWatch;
+    use super::StabilityTracker;
+    use super::WatchOptions;
+
+    fn fingerprint(size: u64) -> Vec<(PathBuf, u64, Option<::std::time::SystemTime>)> {
+        vec![(PathBuf::from("01.flac"), size, None)]
+    }
+
+    #[test]
+    fn test_stability_tracker() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let album_a = PathBuf::from("/inbox/a");
+        let album_b = PathBuf::from("/inbox/b");
+
+        let mut tracker = StabilityTracker::new(Duration::from_secs(10));
+        assert_eq!(None, tracker.next_deadline(at(0)));
+
+        // A new album has to settle first.
+        assert!(tracker.update(vec![(album_a.clone(), fingerprint(1))], at(0)).is_empty());
+        assert_eq!(Some(Duration::from_secs(4)), tracker.next_deadline(at(6)));
+
+        // A change restarts the wait.
+        assert!(tracker.update(vec![(album_a.clone(), fingerprint(2))], at(6)).is_empty());
+        assert!(tracker.update(vec![(album_a.clone(), fingerprint(2)), (album_b.clone(), fingerprint(1))], at(12)).is_empty());
+
+        let produced = tracker.update(vec![(album_a.clone(), fingerprint(2)), (album_b.clone(), fingerprint(1))], at(16));
+        assert_eq!(vec![album_a.clone()], produced);
+
+        // Settled albums are not handed out again unless they change.
+        let produced = tracker.update(vec![(album_a.clone(), fingerprint(2)), (album_b.clone(), fingerprint(1))], at(30));
+        assert_eq!(vec![album_b.clone()], produced);
+        assert_eq!(None, tracker.next_deadline(at(30)));
+
+        assert!(tracker.update(vec![(album_a.clone(), fingerprint(3))], at(31)).is_empty());
+        assert_eq!(vec![album_a.clone()], tracker.update(vec![(album_a.clone(), fingerprint(3))], at(41)));
+    }
+
+    #[test]
+    fn test_scan_inbox() {
+        let dir = tempdir().unwrap();
+        let new_album = dir.path().join("Artist").join("New");
+        let done_album = dir.path().join("Artist").join("Done");
+
+        fs::create_dir_all(&new_album).unwrap();
+        fs::create_dir_all(&done_album).unwrap();
+        fs::create_dir_all(dir.path().join("Empty")).unwrap();
+        fs::write(new_album.join("01.flac"), b"abc").unwrap();
+        fs::write(new_album.join("rip.log"), b"log").unwrap();
+        fs::write(done_album.join("01.flac"), b"abc").unwrap();
+        fs::write(done_album.join("ar-flac-rs.log"), b"report").unwrap();
+
+        let produced = scan_inbox(dir.path()).unwrap();
+
+        assert_eq!(1, produced.len());
+        assert_eq!(new_album, produced[0].0);
+
+        let files = produced[0].1.iter().map(|&(ref path, size, _)| (path.file_name().unwrap().to_str().unwrap(), size)).collect::<Vec<_>>();
+        assert_eq!(vec![("01.flac", 3), ("rip.log", 3)], files);
+    }
+
+    #[test]
+    fn test_move_target() {
+        let inputs_and_expected = vec![
+            (("/inbox/Artist/Album", Verdict::Accurate), Some("/library/accurate/Artist/Album")),
+            (("/inbox/Album", Verdict::Inaccurate), Some("/library/inaccurate/Album")),
+            // Moving the inbox itself would lose the watch on it.
+            (("/inbox", Verdict::Unknown), None),
+            (("/inbox/", Verdict::Unknown), None),
+        ];
+
+        for ((album_dir, verdict), expected) in inputs_and_expected {
+            let produced = move_target(album_dir, "/inbox", "/library", verdict);
+            assert_eq!(expected.map(PathBuf::from), produced);
+        }
+    }
+
+    #[test]
+    fn test_inbox_watch_polling() {
+        let dir = tempdir().unwrap();
+        let album_dir = dir.path().join("Album");
+
+        fs::create_dir_all(&album_dir).unwrap();
+        fs::write(album_dir.join("01.flac"), b"abc").unwrap();
+
+        let options = WatchOptions {
+            action: Action::Label,
+            settle: Duration::from_millis(100),
+            poll_interval: Duration::from_millis(30),
+            notifications: false,
+        };
+
+        let start = Instant::now();
+        let mut inbox_watch = InboxWatch::new(dir.path(), &options);
+        assert!(inbox_watch.settled_albums().is_empty());
+
+        let mut num_waits = 0;
+        let produced = loop {
+            inbox_watch.wait();
+            num_waits += 1;
+
+            let settled = inbox_watch.settled_albums();
+            if !settled.is_empty() {
+                break settled;
+            }
+        };
+
+        assert_eq!(vec![album_dir], produced);
+
+        // Each wait sleeps until the next poll or the settle deadline, instead of rescanning right away.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(num_waits <= 6, "rescanned {} times", num_waits);
+    }
+}
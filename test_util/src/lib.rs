@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::path::PathBuf;
 use std::fs::File;
 use std::io::Read;
 
@@ -10,3 +11,12 @@ pub fn load_bytes<P: AsRef<Path>>(bin_path: P) -> Vec<u8> {
 
     buffer
 }
+
+/// Path to the sample dBAR file, for the 10-track disc `010-0012baaf-0094edfd-8b0b500a`.
+pub fn sample_bin_path() -> PathBuf {
+    PathBuf::from("test_util").join("input").join("bin").join("dBAR-010-0012baaf-0094edfd-8b0b500a.bin")
+}
+
+pub fn load_sample_bin() -> Vec<u8> {
+    load_bytes(sample_bin_path())
+}